@@ -1,18 +1,46 @@
 use crate::artwork::ArtworkInfo;
-use actix_web::web::{Data, Query};
-use actix_web::{get, http, post, web, HttpRequest, HttpResponse, Responder};
+use crate::cache::TtlCache;
+use crate::character;
+use crate::delta::{self, DeltaEncoder};
+use crate::featured::{self, get_featured, parse_date, FEATURED_MAX_RANGE_DAYS};
+use crate::metrics;
+use actix_web::web::{Bytes, Data, Query};
+use actix_web::{delete, get, http, post, put, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use mongodb::Database;
-use serde::Deserialize;
+use percent_encoding::percent_decode_str;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
 use serde_qs;
+use std::collections::HashSet;
+use std::fmt;
 use std::str::from_utf8;
-use tokio::join;
+use tokio::{join, try_join};
+use typed_builder::TypedBuilder;
 
 use crate::db::{
-    get_artwork_count_nsfw, get_artwork_count_r18, get_artwork_count_sfw, get_artwork_count_total,
-    get_artwork_info_by_ids, get_ids, get_latest_upload_time, save_artwork_many,
-    ArtworkQueryOption,
+    delete_featured_slot, delete_webhook, get_artwork_count_404, get_artwork_count_nsfw,
+    get_artwork_count_pending, get_artwork_count_r18, get_artwork_count_sfw,
+    get_artwork_count_total, get_artwork_fields_by_ids, get_artwork_info_by_ids, get_changes,
+    get_character_counts, get_character_covers, get_latest_upload_time, get_random_ids,
+    get_related_characters, get_top_artists, get_trending, get_upload_histogram,
+    get_webhook_deliveries, get_webhooks, insert_webhook, is_artwork_visible, save_artwork_batch,
+    save_artwork_many, save_featured_slot, stream_artworks, stream_ids, validate_artworks,
+    ArtworkFields, ArtworkQueryOption, ArtworkSort, CharacterCount, ExportFilter, FeaturedSlot,
+    IdStream, SavedArtwork, Webhook, TRENDING_MAX_WINDOW_SECS,
 };
+use crate::events::{UploadEvent, UploadEvents, UploadFilter};
+use crate::ndjson::{self, Line, LineSplitter};
+use crate::webhooks::{ContentChange, ContentEvent, Webhooks, MIN_SECRET_LEN};
+use actix_web::guard::GuardContext;
+use futures::future::ready;
+use futures::stream::{self, StreamExt};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::wrappers::IntervalStream;
 
 /// DbSyncToken authorizes database write operations.
 /// The token is preferably provided at runtime via environment variable
@@ -34,185 +62,1715 @@ pub struct ArtworkIdRequest {
     #[serde(rename = "type")]
     art_type: Option<String>,
     character: Option<String>,
+    sort: Option<ArtworkSort>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    since: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    until: Option<i64>,
+    min_likes: Option<i32>,
+    min_loves: Option<i32>,
+    min_views: Option<i32>,
+    element: Option<String>,
+    region: Option<String>,
+    weapon: Option<String>,
+    /// Seed of `sort=random`, today's date (UTC) by default
+    seed: Option<String>,
+    /// `json` or `delta`, overrides the `Accept` header
+    format: Option<String>,
 }
 
-/// ArtworkInfoRequest contains query params for `/api/image-info` endpoint
+impl ArtworkIdRequest {
+    /// Converts the query params into db query options, filtering by the given characters.
+    /// Returns `None` if the character metadata filters leave no character to match
+    fn query_option(self, characters: Vec<String>) -> Option<ArtworkQueryOption> {
+        let characters = expand_characters(
+            characters,
+            self.element.as_deref(),
+            self.region.as_deref(),
+            self.weapon.as_deref(),
+        )?;
+        let art_type = self.art_type.unwrap_or_else(|| "SFW".to_owned());
+        let mut options = ArtworkQueryOption::builder()
+            .characters(characters)
+            .image_type(art_type)
+            .build();
+        options.sort = self.sort;
+        options.offset = self.offset;
+        options.limit = self.limit;
+        options.since = self.since;
+        options.until = self.until;
+        options.min_likes = self.min_likes;
+        options.min_loves = self.min_loves;
+        options.min_views = self.min_views;
+        options.seed = self.seed;
+        Some(options)
+    }
+}
+
+/// expand_characters narrows a character filter down to the catalog characters
+/// of an element, region and weapon, matching their stored names exactly.
+/// Returns `None` if no character is left to match
+fn expand_characters(
+    characters: Vec<String>,
+    element: Option<&str>,
+    region: Option<&str>,
+    weapon: Option<&str>,
+) -> Option<Vec<String>> {
+    if element.is_none() && region.is_none() && weapon.is_none() {
+        return Some(characters);
+    }
+    let partials: Vec<&String> = characters
+        .iter()
+        .filter(|chara| !chara.trim().is_empty())
+        .collect();
+    let patterns: Vec<String> = character::filter(element, region, weapon)
+        .into_iter()
+        .filter(|meta| {
+            partials.is_empty() || partials.iter().any(|chara| meta.matches_partial(chara))
+        })
+        .flat_map(|meta| meta.name_patterns())
+        .collect();
+    if patterns.is_empty() {
+        return None;
+    }
+    Some(patterns)
+}
+
+/// ApiConfig holds request limits applied by the api handlers.
+/// The values are preferably provided at runtime via environment variables
+#[derive(Clone, Debug, TypedBuilder)]
+pub struct ApiConfig {
+    /// Most ids accepted by a single `/api/image-info` request, counting duplicates
+    #[builder(default = 1000)]
+    pub image_info_max_ids: usize,
+}
+
+/// ArtworkInfoRequest contains query params (or the json body) for `/api/image-info` endpoint
+#[derive(Deserialize)]
+pub struct ArtworkInfoRequest {
+    ids: Option<Vec<i64>>,
+    /// `thumb`, `card`, `full` (default) or a comma separated list of fields
+    fields: Option<String>,
+}
+
+/// Number of artists listed by `/api/statistics`
+const STATISTICS_TOP_ARTISTS: u64 = 10;
+
+/// TrendingRequest contains query params for `/api/trending` endpoint
+#[derive(Deserialize)]
+pub struct TrendingRequest {
+    #[serde(rename = "type")]
+    art_type: Option<String>,
+    /// e.g. `24h` (default) or `7d`
+    window: Option<String>,
+    limit: Option<u64>,
+}
+
+/// ChangesRequest contains query params for `/api/changes` endpoint
+#[derive(Deserialize)]
+pub struct ChangesRequest {
+    /// The `next` token of the previous page, 0 or none to start from the beginning
+    since: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Most changes returned at once
+const CHANGES_MAX: i64 = 1000;
+
+/// RelatedCharactersRequest contains query params for `/api/character/{name}/related` endpoint
+#[derive(Deserialize)]
+pub struct RelatedCharactersRequest {
+    limit: Option<usize>,
+}
+
+/// Most characters returned by `/api/character/{name}/related`
+const RELATED_CHARACTERS_MAX: usize = 100;
+
+/// In-process caches of the read endpoints, shared by every worker.
+/// Every cache is dropped when `/api/db/sync` writes
+pub struct ApiCache {
+    /// Id listings, keyed by normalized query options. Shared, since they can be long
+    pub ids: TtlCache<ArtworkQueryOption, Arc<Vec<i64>>>,
+    /// Public `/api/image-info` bodies, keyed by the requested ids and fields
+    pub image_info: TtlCache<(Vec<i64>, ArtworkFields), serde_json::Value>,
+    /// The `/api/statistics` body
+    pub statistics: TtlCache<(), serde_json::Value>,
+    /// Related characters, keyed by the lowercased character name
+    pub related_characters: TtlCache<String, Vec<CharacterCount>>,
+    /// Artwork counts of the `/metrics` gauges, as total, sfw, nsfw and r18
+    pub artwork_counts: TtlCache<(), [u64; 4]>,
+    /// Last write generation seen, to notice syncs handled by other replicas
+    write_generation: AtomicI64,
+}
+
+impl ApiCache {
+    /// `ttl` applies to every cache but related characters, which change slowly and are
+    /// kept for `related_ttl`. Each cache holds at most `capacity` entries, and the id
+    /// listings and image info bodies, whose size depends on the request, at most
+    /// `max_bytes` each
+    pub fn new(ttl: Duration, related_ttl: Duration, capacity: usize, max_bytes: usize) -> Self {
+        ApiCache {
+            ids: TtlCache::new("ids", ttl, capacity)
+                .weighted(max_bytes, |ids| ids.len() * std::mem::size_of::<i64>()),
+            image_info: TtlCache::new("image_info", ttl, capacity).weighted(max_bytes, json_weight),
+            statistics: TtlCache::new("statistics", ttl, 1),
+            related_characters: TtlCache::new("related_characters", related_ttl, capacity),
+            artwork_counts: TtlCache::new("artwork_counts", ttl, 1),
+            write_generation: AtomicI64::new(0),
+        }
+    }
+
+    /// Drops every cached response if the write generation changed since the last call
+    pub fn observe_write_generation(&self, generation: i64) {
+        if self.write_generation.swap(generation, Ordering::SeqCst) != generation {
+            self.invalidate();
+        }
+    }
+
+    /// Drops every cached response
+    pub fn invalidate(&self) {
+        self.ids.invalidate();
+        self.image_info.invalidate();
+        self.statistics.invalidate();
+        self.related_characters.invalidate();
+        self.artwork_counts.invalidate();
+    }
+}
+
+/// Approximate size of a json value in bytes, as serialized
+fn json_weight(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::Null | serde_json::Value::Bool(_) => 5,
+        serde_json::Value::Number(_) => 8,
+        serde_json::Value::String(string) => string.len() + 2,
+        serde_json::Value::Array(items) => {
+            2 + items
+                .iter()
+                .map(|item| json_weight(item) + 1)
+                .sum::<usize>()
+        }
+        serde_json::Value::Object(map) => {
+            2 + map
+                .iter()
+                .map(|(key, value)| key.len() + 4 + json_weight(value))
+                .sum::<usize>()
+        }
+    }
+}
+
+/// RandomRequest contains query params for `/api/random` endpoint
+#[derive(Deserialize)]
+pub struct RandomRequest {
+    #[serde(rename = "type")]
+    art_type: Option<String>,
+    character: Option<String>,
+    count: Option<u64>,
+    seed: Option<String>,
+    element: Option<String>,
+    region: Option<String>,
+    weapon: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    since: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    until: Option<i64>,
+    min_likes: Option<i32>,
+    min_loves: Option<i32>,
+    min_views: Option<i32>,
+}
+
+/// parse_timestamp reads unix seconds, an ISO 8601 date time (`2022-03-08T12:00:00Z`)
+/// or an ISO 8601 date (`2022-03-08`, midnight UTC) into unix seconds
+fn parse_timestamp(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Some(timestamp);
+    }
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        return Some(date_time.timestamp());
+    }
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Some(Utc.from_utc_datetime(&date_time).timestamp());
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date_time| Utc.from_utc_datetime(&date_time).timestamp())
+}
+
+/// deserialize_timestamp deserializes an optional query param with `parse_timestamp`
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => parse_timestamp(&value).map(Some).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "invalid timestamp {:?}, expected unix seconds or ISO 8601",
+                value
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// ExportRequest contains query params for `/api/admin/export` endpoint
+#[derive(Deserialize)]
+pub struct ExportRequest {
+    rating: Option<String>,
+    status: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    since: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    until: Option<i64>,
+}
+
+/// Artworks serialized per chunk of an export stream
+const EXPORT_STREAM_CHUNK: usize = 256;
+
+/// FeaturedRangeRequest contains query params for `/api/featured` endpoint
+#[derive(Deserialize)]
+pub struct FeaturedRangeRequest {
+    from: String,
+    to: String,
+}
+
+/// FeaturedScheduleRequest is the json body of `/api/admin/featured/{date}`
+#[derive(Deserialize)]
+pub struct FeaturedScheduleRequest {
+    art_id: i64,
+}
+
+/// WebhookRequest is the json body of `/api/admin/webhooks`
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    url: String,
+    events: Vec<ContentEvent>,
+    secret: String,
+}
+
+/// WebhookDeliveriesRequest contains query params for `/api/admin/webhooks/{id}/deliveries`
+#[derive(Deserialize)]
+pub struct WebhookDeliveriesRequest {
+    limit: Option<i64>,
+}
+
+/// Most delivery log entries returned at once
+const WEBHOOK_DELIVERIES_MAX: i64 = 200;
+
+/// api_health implies the application is ready.
+/// This is for docker health check
+#[get("/api/health")]
+pub async fn api_health() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body("{}")
+}
+
+/// api_all returns all artwork ids
+#[get("/api/characters")]
+pub async fn api_all(
+    db: Data<Database>,
+    cache: Data<ApiCache>,
+    Query(info): Query<ArtworkIdRequest>,
+    req: HttpRequest,
+) -> impl Responder {
+    let format = match IdListFormat::negotiate(info.format.as_deref(), &req) {
+        Some(format) => format,
+        None => return unknown_id_list_format(),
+    };
+    let characters = match &info.character {
+        Some(character) => vec![character.to_owned()],
+        None => vec![],
+    };
+    let options = match info.query_option(characters) {
+        Some(options) => options,
+        None => return id_list_response(Box::pin(stream::empty()), format),
+    };
+    match id_list_stream(&db, &cache, options).await {
+        Ok(ids) => id_list_response(ids, format),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// Ids written per chunk of a streamed id listing
+const ID_STREAM_CHUNK: usize = 1024;
+
+/// id_list_stream lists artwork ids through the id cache. Cached listings are streamed from
+/// the shared list, so that concurrent requests don't copy it.
+/// Otherwise the ids are streamed from the cursor, and copied into the cache only until the
+/// copy outgrows the cache's byte bound, so that memory stays flat for long listings
+async fn id_list_stream(
+    db: &Database,
+    cache: &Data<ApiCache>,
+    options: ArtworkQueryOption,
+) -> Result<IdStream, Box<dyn std::error::Error>> {
+    let options = options.normalized();
+    if let Some(id_list) = cache.ids.get(&options) {
+        return Ok(Box::pin(stream::iter(
+            (0..id_list.len()).map(move |index| Ok(id_list[index])),
+        )));
+    }
+    let generation = cache.ids.generation();
+    let ids = stream_ids(db, options.clone()).await?;
+    Ok(copy_ids_into_cache(ids, cache.clone(), options, generation))
+}
+
+/// copy_ids_into_cache passes the ids through, keeping a copy of them that is stored in the
+/// id cache once the stream ends. The copy is dropped as soon as it outgrows the cache's byte
+/// bound, or the stream fails
+fn copy_ids_into_cache(
+    ids: IdStream,
+    cache: Data<ApiCache>,
+    options: ArtworkQueryOption,
+    generation: u64,
+) -> IdStream {
+    let max_ids = cache.ids.max_bytes() / std::mem::size_of::<i64>();
+    let state = (ids, Some(Vec::new()), cache, options);
+    Box::pin(stream::unfold(
+        state,
+        move |(mut ids, mut copy, cache, options)| async move {
+            match ids.next().await {
+                Some(Ok(art_id)) => {
+                    match copy.as_mut() {
+                        Some(copy) if copy.len() < max_ids => copy.push(art_id),
+                        _ => copy = None,
+                    }
+                    Some((Ok(art_id), (ids, copy, cache, options)))
+                }
+                Some(Err(e)) => Some((Err(e), (ids, None, cache, options))),
+                None => {
+                    if let Some(copy) = copy {
+                        cache
+                            .ids
+                            .insert_unless_invalidated(options, Arc::new(copy), generation);
+                    }
+                    None
+                }
+            }
+        },
+    ))
+}
+
+/// Representation of id listings
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IdListFormat {
+    /// `{"data":[...]}`
+    Json,
+    /// Delta encoded varints, see `delta::DeltaEncoder`
+    Delta,
+}
+
+impl IdListFormat {
+    /// Picks the format from the `format` query param, or else the `Accept` header.
+    /// Returns `None` for unknown formats
+    fn negotiate(format: Option<&str>, req: &HttpRequest) -> Option<Self> {
+        match format.map(|format| format.trim().to_lowercase()).as_deref() {
+            Some("json") => Some(IdListFormat::Json),
+            Some("delta") => Some(IdListFormat::Delta),
+            Some(_) => None,
+            None => {
+                let accept = req
+                    .headers()
+                    .get(http::header::ACCEPT)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default();
+                if accepts_media_type(accept, delta::CONTENT_TYPE) {
+                    Some(IdListFormat::Delta)
+                } else {
+                    Some(IdListFormat::Json)
+                }
+            }
+        }
+    }
+}
+
+/// unknown_id_list_format responds to id listings asking for an unknown `format`
+fn unknown_id_list_format() -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/json")
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .body(json!({ "message": "format must be json or delta" }).to_string())
+}
+
+/// accepts_media_type checks whether an `Accept` header explicitly lists a media type,
+/// without a zero quality
+fn accepts_media_type(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        let listed = params
+            .next()
+            .map(|essence| essence.eq_ignore_ascii_case(media_type))
+            .unwrap_or(false);
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .map(|quality| quality <= 0.0)
+                .unwrap_or(false)
+        });
+        listed && !refused
+    })
+}
+
+/// id_list_response writes the ids in the given format as they come in.
+/// A cursor error aborts the response, since its status was already sent
+fn id_list_response(ids: IdStream, format: IdListFormat) -> HttpResponse {
+    let mut first = true;
+    let mut encoder = DeltaEncoder::new();
+    let ids = ids.ready_chunks(ID_STREAM_CHUNK).map(move |chunk| {
+        let mut buffer = Vec::new();
+        for art_id in chunk {
+            let art_id = art_id.map_err(|e| {
+                log::error!("Stream ids cursor error {:?}", e);
+                actix_web::error::ErrorInternalServerError(e)
+            })?;
+            match format {
+                IdListFormat::Json => {
+                    if !first {
+                        buffer.push(b',');
+                    }
+                    first = false;
+                    buffer.extend_from_slice(art_id.to_string().as_bytes());
+                }
+                IdListFormat::Delta => encoder.push(art_id, &mut buffer),
+            }
+        }
+        Ok::<_, actix_web::Error>(Bytes::from(buffer))
+    });
+    let (content_type, prefix, suffix): (_, &'static [u8], &'static [u8]) = match format {
+        IdListFormat::Json => ("application/json", b"{\"data\":[", b"]}"),
+        IdListFormat::Delta => (delta::CONTENT_TYPE, b"", b""),
+    };
+    let body = stream::once(ready(Ok(Bytes::from_static(prefix))))
+        .chain(ids)
+        .chain(stream::once(ready(Ok(Bytes::from_static(suffix)))));
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .streaming(body)
+}
+
+/// empty_id_list responds to id listings whose filters can't match any artwork
+fn empty_id_list() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .body(json!({ "data": [] }).to_string())
+}
+
+/// api_character_ids returns artwork ids related to a specific character
+#[get("/api/character/{name}")]
+pub async fn api_character_ids(
+    db: Data<Database>,
+    cache: Data<ApiCache>,
+    params: web::Path<(String,)>,
+    Query(info): Query<ArtworkIdRequest>,
+    req: HttpRequest,
+) -> impl Responder {
+    let format = match IdListFormat::negotiate(info.format.as_deref(), &req) {
+        Some(format) => format,
+        None => return unknown_id_list_format(),
+    };
+    let (name,) = params.into_inner();
+    let options = match info.query_option(vec![name]) {
+        Some(options) => options,
+        None => return id_list_response(Box::pin(stream::empty()), format),
+    };
+    match id_list_stream(&db, &cache, options).await {
+        Ok(ids) => id_list_response(ids, format),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string()}).to_string()),
+    }
+}
+
+/// api_character_related returns the characters most often drawn together with a character,
+/// with the number of shared artworks per rating
+#[get("/api/character/{name}/related")]
+pub async fn api_character_related(
+    db: Data<Database>,
+    cache: Data<ApiCache>,
+    params: web::Path<(String,)>,
+    Query(info): Query<RelatedCharactersRequest>,
+) -> impl Responder {
+    let (name,) = params.into_inner();
+    let limit = info.limit.unwrap_or(10).min(RELATED_CHARACTERS_MAX);
+    // Aliases share the cache entry of the character they refer to
+    let (key, patterns) = match character::find(&name) {
+        Some(meta) => (meta.name.to_lowercase(), meta.name_patterns()),
+        None => (
+            name.trim().to_lowercase(),
+            vec![format!("^{}$", character::escape_regex(name.trim()))],
+        ),
+    };
+    let related = cache
+        .related_characters
+        .get_or_try_insert_with(key, || {
+            get_related_characters(&db, &patterns, RELATED_CHARACTERS_MAX)
+        })
+        .await;
+    let related = match related {
+        Ok(related) => related,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+                .body(json!({ "message": e.to_string() }).to_string())
+        }
+    };
+    let related: Vec<&CharacterCount> = related.iter().take(limit).collect();
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .body(json!({ "data": related }).to_string())
+}
+
+/// api_image_info takes a list of ids and returns the corresponding artwork metadata.
+/// Ids without a document are listed under `missing`, ids of documents that failed to
+/// deserialize under `invalid_ids`. Authorized (admin) requests also get `invalid`, the failed
+/// documents with their errors
+#[get("/api/image-info")]
+pub async fn api_image_info(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    config: Data<ApiConfig>,
+    cache: Data<ApiCache>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Need to explicitly parse the query string since they're arrays
+    // https://github.com/samscott89/serde_qs/blob/main/examples/introduction.rs
+    // The depth limits nesting (`ids[]` is depth 1), not the number of ids
+    let query = req.query_string();
+    // Counted first, so that an oversized list isn't parsed at all
+    if count_query_ids(query) > config.image_info_max_ids {
+        return too_many_ids(config.image_info_max_ids);
+    }
+    let qs = serde_qs::Config::new(2, false);
+    match qs.deserialize_str::<ArtworkInfoRequest>(query) {
+        Ok(info) => image_info_response(&db, &db_sync_token, &config, &cache, &req, info).await,
+        Err(e) => HttpResponse::BadRequest()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_image_info_batch is the POST variant of `/api/image-info`.
+/// It takes the ids as a json body, for batches that would overflow url length limits
+#[post("/api/image-info")]
+pub async fn api_image_info_batch(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    config: Data<ApiConfig>,
+    cache: Data<ApiCache>,
+    body: Bytes,
+    req: HttpRequest,
+) -> impl Responder {
+    let max_ids = config.image_info_max_ids;
+    let mut deserializer = serde_json::Deserializer::from_slice(&body);
+    let info = ImageInfoBody { max_ids }
+        .deserialize(&mut deserializer)
+        .and_then(|info| deserializer.end().map(|_| info));
+    match info {
+        Ok(info) => image_info_response(&db, &db_sync_token, &config, &cache, &req, info).await,
+        Err(e) if e.to_string().starts_with(TOO_MANY_IDS) => too_many_ids(max_ids),
+        Err(e) => HttpResponse::BadRequest()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// Start of the error of an image info request with too many ids
+const TOO_MANY_IDS: &str = "Too many ids";
+
+/// too_many_ids responds to image info requests with more than `max_ids` ids
+fn too_many_ids(max_ids: usize) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/json")
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .body(
+            json!({ "message": format!("{}: at most {} allowed", TOO_MANY_IDS, max_ids) })
+                .to_string(),
+        )
+}
+
+/// Number of ids in an `/api/image-info` query string (`ids[]=1&ids[]=2`, `ids[0]=1`, ...).
+/// Keys are percent-decoded first, as serde_qs does, so `%69ds%5B%5D=1` counts too
+fn count_query_ids(query: &str) -> usize {
+    query
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            let key = percent_decode_str(key).decode_utf8_lossy();
+            key == "ids" || key.starts_with("ids[")
+        })
+        .count()
+}
+
+/// Reads the json body of `/api/image-info`, failing as soon as `ids` holds more than
+/// `max_ids` ids instead of after reading them all
+struct ImageInfoBody {
+    max_ids: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for ImageInfoBody {
+    type Value = ArtworkInfoRequest;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ImageInfoBody {
+    type Value = ArtworkInfoRequest;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object with ids and fields")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut info = ArtworkInfoRequest {
+            ids: None,
+            fields: None,
+        };
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "ids" => info.ids = map.next_value_seed(BoundedIds(self.max_ids))?,
+                "fields" => info.fields = map.next_value()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// The `ids` of `ImageInfoBody`, at most this many
+struct BoundedIds(usize);
+
+impl<'de> DeserializeSeed<'de> for BoundedIds {
+    type Value = Option<Vec<i64>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_option(self)
+    }
+}
+
+impl<'de> Visitor<'de> for BoundedIds {
+    type Value = Option<Vec<i64>>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of ids")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut ids = Vec::with_capacity(seq.size_hint().unwrap_or_default().min(self.0));
+        while let Some(id) = seq.next_element::<i64>()? {
+            if ids.len() == self.0 {
+                return Err(de::Error::custom(TOO_MANY_IDS));
+            }
+            ids.push(id);
+        }
+        Ok(Some(ids))
+    }
+}
+
+/// image_info_response refuses more than `image_info_max_ids` ids, deduplicates them,
+/// then looks up the artworks
+async fn image_info_response(
+    db: &Database,
+    db_sync_token: &DbSyncToken,
+    config: &ApiConfig,
+    cache: &ApiCache,
+    req: &HttpRequest,
+    info: ArtworkInfoRequest,
+) -> HttpResponse {
+    let id_list = info.ids.unwrap_or_default();
+    if id_list.len() > config.image_info_max_ids {
+        return too_many_ids(config.image_info_max_ids);
+    }
+    let id_list = dedup_ids(id_list);
+    let fields = match ArtworkFields::parse(info.fields.as_deref().unwrap_or_default()) {
+        Ok(fields) => fields,
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+                .body(json!({ "message": e }).to_string())
+        }
+    };
+    // Admin responses include the invalid documents, so they bypass the cache
+    let is_admin = validate_db_sync_token(db_sync_token.token(), req.headers()).is_ok();
+    let body = if is_admin {
+        image_info_body(db, id_list, &fields, true).await
+    } else {
+        cache
+            .image_info
+            .get_or_try_insert_with((id_list.clone(), fields.clone()), || {
+                image_info_body(db, id_list, &fields, false)
+            })
+            .await
+    };
+    match body {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(body.to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// image_info_body looks up the requested fields of the artworks.
+/// The ids of documents that failed to deserialize are listed under `invalid_ids`,
+/// the documents with their errors under `invalid` only when `with_invalid` is set
+async fn image_info_body(
+    db: &Database,
+    id_list: Vec<i64>,
+    fields: &ArtworkFields,
+    with_invalid: bool,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let (mut body, invalid) = match fields.projection() {
+        Some(projection) => {
+            let lookup = get_artwork_fields_by_ids(db, id_list, projection).await?;
+            let body = json!({ "data": lookup.artworks, "missing": lookup.missing });
+            (body, lookup.invalid)
+        }
+        None => {
+            let lookup = get_artwork_info_by_ids(db, id_list).await?;
+            let body = json!({ "data": lookup.artworks, "missing": lookup.missing });
+            (body, lookup.invalid)
+        }
+    };
+    let invalid_ids: Vec<i64> = invalid
+        .iter()
+        .filter_map(|invalid| invalid.art_id)
+        .collect();
+    body["invalid_ids"] = json!(invalid_ids);
+    if with_invalid {
+        body["invalid"] = json!(invalid);
+    }
+    Ok(body)
+}
+
+/// dedup_ids removes repeated ids, keeping the first occurrence of each
+fn dedup_ids(id_list: Vec<i64>) -> Vec<i64> {
+    let mut seen = HashSet::with_capacity(id_list.len());
+    id_list
+        .into_iter()
+        .filter(|art_id| seen.insert(*art_id))
+        .collect()
+}
+
+/// api_random picks random artwork ids.
+/// Given a `seed` the picks are reproducible, e.g. the date yields the same picks for a day
+#[get("/api/random")]
+pub async fn api_random(db: Data<Database>, Query(info): Query<RandomRequest>) -> impl Responder {
+    let characters = match info.character {
+        Some(character) => vec![character],
+        None => vec![],
+    };
+    let characters = match expand_characters(
+        characters,
+        info.element.as_deref(),
+        info.region.as_deref(),
+        info.weapon.as_deref(),
+    ) {
+        Some(characters) => characters,
+        None => return empty_id_list(),
+    };
+    let art_type = info.art_type.unwrap_or_else(|| "SFW".to_owned());
+    let mut options = ArtworkQueryOption::builder()
+        .characters(characters)
+        .image_type(art_type)
+        .build();
+    options.since = info.since;
+    options.until = info.until;
+    options.min_likes = info.min_likes;
+    options.min_loves = info.min_loves;
+    options.min_views = info.min_views;
+    let count = info.count.unwrap_or(1).min(100);
+    match get_random_ids(&db, &options, count, info.seed.as_deref()).await {
+        Ok(id_list) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "data": id_list }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_trending ranks artworks by engagement gained over a recent window
+#[get("/api/trending")]
+pub async fn api_trending(
+    db: Data<Database>,
+    Query(info): Query<TrendingRequest>,
+) -> impl Responder {
+    let window_secs = match parse_window(info.window.as_deref().unwrap_or("24h")) {
+        Some(window_secs) if window_secs <= TRENDING_MAX_WINDOW_SECS => window_secs,
+        _ => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+                .body(
+                    json!({ "message": "window must look like 24h or 7d, and be at most 7d" })
+                        .to_string(),
+                )
+        }
+    };
+    let art_type = info.art_type.unwrap_or_else(|| "SFW".to_owned());
+    let limit = info.limit.unwrap_or(50).min(500);
+    match get_trending(&db, &art_type, window_secs, limit).await {
+        Ok(trending) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "data": trending }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_changes lists artworks created, updated or hidden after a change token, oldest first.
+/// Every artwork appears once with its latest change, so mirrors can sync incrementally
+/// by passing the returned `next` token as `since` until `has_more` is false
+#[get("/api/changes")]
+pub async fn api_changes(db: Data<Database>, Query(info): Query<ChangesRequest>) -> impl Responder {
+    let since = info.since.unwrap_or(0).max(0);
+    let limit = info.limit.unwrap_or(CHANGES_MAX).clamp(1, CHANGES_MAX);
+    match get_changes(&db, since, limit).await {
+        Ok(page) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "data": page }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// parse_window converts a window such as `24h` or `7d` into seconds
+fn parse_window(window: &str) -> Option<i64> {
+    let window = window.trim();
+    let unit_secs = match window.chars().last()? {
+        'h' | 'H' => 60 * 60,
+        'd' | 'D' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let amount = window[..window.len() - 1].parse::<i64>().ok()?;
+    if amount <= 0 {
+        return None;
+    }
+    amount.checked_mul(unit_secs)
+}
+
+/// api_featured_today returns today's featured artwork
+#[get("/api/featured/today")]
+pub async fn api_featured_today(db: Data<Database>) -> impl Responder {
+    let today = featured::today();
+    match get_featured(&db, today, today).await {
+        Ok(featured) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "data": featured.into_iter().next() }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_featured returns the featured artworks of a date range (`YYYY-MM-DD`, inclusive)
+#[get("/api/featured")]
+pub async fn api_featured(
+    db: Data<Database>,
+    Query(info): Query<FeaturedRangeRequest>,
+) -> impl Responder {
+    let range = match (parse_date(&info.from), parse_date(&info.to)) {
+        (Some(from), Some(to))
+            if from <= to && (to - from).num_days() < FEATURED_MAX_RANGE_DAYS =>
+        {
+            Some((from, to))
+        }
+        _ => None,
+    };
+    let (from, to) = match range {
+        Some(range) => range,
+        None => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+                .body(
+                    json!({
+                        "message": format!(
+                            "from and to must be YYYY-MM-DD dates, from <= to, spanning at most {} days",
+                            FEATURED_MAX_RANGE_DAYS,
+                        )
+                    })
+                    .to_string(),
+                )
+        }
+    };
+    match get_featured(&db, from, to).await {
+        Ok(featured) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "data": featured }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_admin_schedule_featured schedules an SFW artwork into a date slot, replacing any previous pick
+#[put("/api/admin/featured/{date}")]
+pub async fn api_admin_schedule_featured(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    params: web::Path<(String,)>,
+    web::Json(info): web::Json<FeaturedScheduleRequest>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    let (date,) = params.into_inner();
+    let date = match parse_date(&date) {
+        Some(date) => date.format(featured::DATE_FORMAT).to_string(),
+        None => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .body(json!({ "message": "date must be YYYY-MM-DD" }).to_string())
+        }
+    };
+    match is_artwork_visible(&db, info.art_id, "SFW").await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(
+            json!({ "message": format!("artwork {} is not a visible SFW artwork", info.art_id) })
+                .to_string(),
+        ),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .body(json!({ "message": e.to_string() }).to_string())
+        }
+    }
+    let slot = FeaturedSlot {
+        date,
+        art_id: info.art_id,
+        source: "scheduled".to_owned(),
+    };
+    match save_featured_slot(&db, &slot, true).await {
+        Ok(()) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "data": slot }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_admin_unschedule_featured clears a date slot, so the date falls back to an automatic pick
+#[delete("/api/admin/featured/{date}")]
+pub async fn api_admin_unschedule_featured(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    params: web::Path<(String,)>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    let (date,) = params.into_inner();
+    let date = match parse_date(&date) {
+        Some(date) => date.format(featured::DATE_FORMAT).to_string(),
+        None => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .body(json!({ "message": "date must be YYYY-MM-DD" }).to_string())
+        }
+    };
+    match delete_featured_slot(&db, &date).await {
+        Ok(true) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "message": "ok" }).to_string()),
+        Ok(false) => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json!({ "message": format!("no featured slot on {}", date) }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_character_list lists every character drawn in a visible artwork,
+/// with counts and the most recent artwork as cover per rating, and catalog metadata if known
+#[get("/api/character-list")]
+pub async fn api_character_list(db: Data<Database>) -> impl Responder {
+    let (counts, covers) = join! {
+        get_character_counts(&db),
+        get_character_covers(&db),
+    };
+    let (counts, covers) = match (counts, covers) {
+        (Ok(counts), Ok(covers)) => (counts, covers),
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+                .body(json!({ "message": e.to_string() }).to_string())
+        }
+    };
+    let characters: Vec<serde_json::Value> = counts
+        .into_iter()
+        .map(|count| {
+            json!({
+                "name": count.name,
+                "sfw": count.sfw,
+                "nsfw": count.nsfw,
+                "r18": count.r18,
+                "covers": covers.get(&count.name).cloned().unwrap_or_default(),
+                "meta": character::find(&count.name),
+            })
+        })
+        .collect();
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .body(json!({ "data": characters }).to_string())
+}
+
+/// api_statistics tracks metadata on the collection level:
+/// artwork counts, per-character counts per rating, top artists and upload histograms
+#[get("/api/statistics")]
+pub async fn api_statistics(db: Data<Database>, cache: Data<ApiCache>) -> impl Responder {
+    match cache
+        .statistics
+        .get_or_try_insert_with((), || statistics(&db))
+        .await
+    {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(body.to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// statistics runs the statistics queries concurrently and fails if any of them fails
+async fn statistics(db: &Database) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let now = Utc::now().timestamp();
+    let results = join! {
+        get_artwork_count_total(db),
+        get_latest_upload_time(db),
+        get_artwork_count_sfw(db),
+        get_artwork_count_nsfw(db),
+        get_artwork_count_r18(db),
+        get_artwork_count_pending(db),
+        get_artwork_count_404(db),
+        get_character_counts(db),
+        get_top_artists(db, STATISTICS_TOP_ARTISTS),
+        get_upload_histogram(db, now - 30 * 24 * 60 * 60, "%Y-%m-%d"),
+        get_upload_histogram(db, now - 12 * 7 * 24 * 60 * 60, "%G-W%V"),
+    };
+    Ok(json! ({
+        "data": {
+            "artwork": {
+                "total": results.0?,
+                "sfw": results.2?,
+                "nsfw": results.3?,
+                "r18": results.4?,
+                "latestUploadTime": results.1?,
+                "pendingModeration": results.5?,
+                "notFound": results.6?,
+            },
+            "characters": results.7?,
+            "topArtists": results.8?,
+            "uploads": {
+                "daily": results.9?,
+                "weekly": results.10?,
+            },
+        }
+    }))
+}
+
+/// api_metrics exposes prometheus metrics.
+/// Artwork count gauges are counted again after a db sync, or once the response cache ttl passed
+#[get("/metrics")]
+pub async fn api_metrics(db: Data<Database>, cache: Data<ApiCache>) -> impl Responder {
+    let counts = cache
+        .artwork_counts
+        .get_or_try_insert_with((), || async {
+            let (total, sfw, nsfw, r18) = try_join! {
+                get_artwork_count_total(&db),
+                get_artwork_count_sfw(&db),
+                get_artwork_count_nsfw(&db),
+                get_artwork_count_r18(&db),
+            }?;
+            Ok::<_, Box<dyn std::error::Error>>([total, sfw, nsfw, r18])
+        })
+        .await;
+    match counts {
+        Ok(counts) => {
+            for (rating, count) in ["total", "sfw", "nsfw", "r18"].into_iter().zip(counts) {
+                metrics::set_artwork_count(rating, count);
+            }
+        }
+        Err(e) => log::warn!("Artwork count {:?}", e),
+    }
+    match metrics::render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_db_sync accepts authorized updates to the db
+#[post("/api/db/sync")]
+pub async fn api_db_sync(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    cache: Data<ApiCache>,
+    upload_events: Data<UploadEvents>,
+    webhooks: Data<Webhooks>,
+    web::Json(artwork_list): web::Json<Vec<ArtworkInfo>>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(
+                json!({
+                    "message": err.to_string(),
+                })
+                .to_string(),
+            );
+    }
+    let result = save_artwork_many(&db, artwork_list).await;
+    // Some artworks may have been written even if others failed
+    cache.invalidate();
+    match result {
+        Ok(saved) => {
+            announce_saved(&db, &upload_events, &webhooks, &saved).await;
+            HttpResponse::Ok().content_type("application/json").body(
+                json!({
+                    "message": "ok",
+                })
+                .to_string(),
+            )
+        }
+        Err(_) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(
+                json! ({
+                    "message": "error",
+                })
+                .to_string(),
+            ),
+    }
+}
+
+/// announce_saved notifies upload stream subscribers and webhooks of the artworks a sync wrote
+async fn announce_saved(
+    db: &Database,
+    upload_events: &UploadEvents,
+    webhooks: &Webhooks,
+    saved: &[SavedArtwork],
+) {
+    let events = saved.iter().filter_map(UploadEvent::from_saved).collect();
+    upload_events.publish(events);
+    let changes: Vec<ContentChange> = saved.iter().filter_map(ContentChange::from_saved).collect();
+    webhooks.dispatch(db, &changes).await;
+}
+
+/// Artworks written at once by a streaming import
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// Longest line accepted by a streaming import
+const IMPORT_MAX_LINE_BYTES: usize = 1 << 20;
+
+/// Most line errors listed in an import summary, the rest are only counted
+const IMPORT_MAX_ERRORS: usize = 1000;
+
+/// A line a streaming import couldn't save
+#[derive(Serialize)]
+struct ImportError {
+    line: usize,
+    art_id: Option<i64>,
+    error: String,
+}
+
+/// Outcome of a streaming import
+#[derive(Default, Serialize)]
+struct ImportSummary {
+    /// Lines read, blank ones included
+    lines: usize,
+    saved: usize,
+    failed: usize,
+    errors: Vec<ImportError>,
+}
+
+impl ImportSummary {
+    fn fail(&mut self, line: usize, art_id: Option<i64>, error: String) {
+        self.failed += 1;
+        if self.errors.len() < IMPORT_MAX_ERRORS {
+            self.errors.push(ImportError {
+                line,
+                art_id,
+                error,
+            });
+        }
+    }
+}
+
+/// Options of a streaming import
 #[derive(Deserialize)]
-pub struct ArtworkInfoRequest {
-    ids: Option<Vec<i64>>,
+pub struct ImportRequest {
+    /// Whether saved artworks are announced to upload streams and webhooks like a db sync,
+    /// default true. Backfills and restores turn it off
+    announce: Option<bool>,
 }
 
-/// api_health implies the application is ready.
-/// This is for docker health check
-#[get("/api/health")]
-pub async fn api_health() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .body("{}")
+/// is_ndjson routes requests with a newline-delimited json body
+fn is_ndjson(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|media_type| media_type.trim().eq_ignore_ascii_case(ndjson::CONTENT_TYPE))
+        .unwrap_or(false)
 }
 
-/// api_all returns all artwork ids
-#[get("/api/characters")]
-pub async fn api_all(db: Data<Database>, Query(info): Query<ArtworkIdRequest>) -> impl Responder {
-    let characters = match info.character {
-        Some(character) => vec![character],
-        None => vec![],
-    };
-    let art_type = info.art_type.unwrap_or_else(|| "SFW".to_owned());
-    let options = ArtworkQueryOption::builder()
-        .characters(characters)
-        .image_type(art_type)
-        .build();
-    let get_id_result = get_ids(&db, options).await;
-    match get_id_result {
-        Ok(id_list) => HttpResponse::Ok()
+/// api_db_sync_import accepts authorized updates to the db as newline-delimited json,
+/// one `ArtworkInfo` per line. Lines are validated as they arrive and written in batches,
+/// so the body is never buffered whole. Responds with a summary listing the failed lines
+#[post("/api/db/sync", guard = "is_ndjson")]
+#[allow(clippy::too_many_arguments)]
+pub async fn api_db_sync_import(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    cache: Data<ApiCache>,
+    upload_events: Data<UploadEvents>,
+    webhooks: Data<Webhooks>,
+    Query(info): Query<ImportRequest>,
+    mut payload: web::Payload,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    let announce = info
+        .announce
+        .unwrap_or(true)
+        .then(|| (upload_events.get_ref(), webhooks.get_ref()));
+    let mut splitter = LineSplitter::new(IMPORT_MAX_LINE_BYTES);
+    let mut summary = ImportSummary::default();
+    let mut batch: Vec<(usize, ArtworkInfo)> = vec![];
+    let mut payload_error = None;
+    loop {
+        let lines = match payload.next().await {
+            Some(Ok(chunk)) => splitter.push(&chunk),
+            Some(Err(e)) => {
+                payload_error = Some(e.to_string());
+                break;
+            }
+            None => break,
+        };
+        for line in lines {
+            parse_import_line(line, &mut summary, &mut batch);
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                let full_batch = std::mem::take(&mut batch);
+                import_batch(&db, &cache, announce, full_batch, &mut summary).await;
+            }
+        }
+    }
+    // An interrupted body may end in the middle of a line, which is left out
+    if payload_error.is_none() {
+        if let Some(line) = splitter.finish() {
+            parse_import_line(line, &mut summary, &mut batch);
+        }
+    }
+    if !batch.is_empty() {
+        import_batch(&db, &cache, announce, batch, &mut summary).await;
+    }
+    summary.lines = splitter.lines();
+    summary.errors.sort_by_key(|error| error.line);
+    match payload_error {
+        Some(message) => HttpResponse::BadRequest()
             .content_type("application/json")
-            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
             .body(
-                json! ({
-                    "data": id_list,
+                json!({
+                    "message": format!("body interrupted: {}", message),
+                    "data": summary,
                 })
                 .to_string(),
             ),
+        None => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "message": "ok", "data": summary }).to_string()),
+    }
+}
+
+/// parse_import_line adds a line of a streaming import to the batch, or its error to the summary
+fn parse_import_line(
+    line: Line,
+    summary: &mut ImportSummary,
+    batch: &mut Vec<(usize, ArtworkInfo)>,
+) {
+    match line {
+        Line::Data { number, bytes } => match serde_json::from_slice::<ArtworkInfo>(&bytes) {
+            Ok(artwork) => batch.push((number, artwork)),
+            Err(e) => {
+                // Point at the artwork if at least its id is readable
+                let art_id = serde_json::from_slice::<serde_json::Value>(&bytes)
+                    .ok()
+                    .and_then(|value| value.get("art_id")?.as_i64());
+                summary.fail(number, art_id, e.to_string());
+            }
+        },
+        Line::TooLong { number } => {
+            summary.fail(
+                number,
+                None,
+                format!("line longer than {} bytes", IMPORT_MAX_LINE_BYTES),
+            );
+        }
+    }
+}
+
+/// import_batch writes a batch of a streaming import and, given where to, announces it
+/// like a db sync
+async fn import_batch(
+    db: &Database,
+    cache: &ApiCache,
+    announce: Option<(&UploadEvents, &Webhooks)>,
+    batch: Vec<(usize, ArtworkInfo)>,
+    summary: &mut ImportSummary,
+) {
+    let (numbers, artworks): (Vec<usize>, Vec<ArtworkInfo>) = batch.into_iter().unzip();
+    let art_ids: Vec<i64> = artworks.iter().map(|artwork| artwork.art_id).collect();
+    let results = save_artwork_batch(db, artworks).await;
+    cache.invalidate();
+    let mut saved = vec![];
+    for ((number, art_id), result) in numbers.into_iter().zip(art_ids).zip(results) {
+        match result {
+            Ok(artwork) => saved.push(artwork),
+            Err(e) => summary.fail(number, Some(art_id), e.to_string()),
+        }
+    }
+    summary.saved += saved.len();
+    if let Some((upload_events, webhooks)) = announce {
+        announce_saved(db, upload_events, webhooks, &saved).await;
+    }
+}
+
+/// Interval of the comments keeping upload streams alive through proxies
+const UPLOAD_STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// api_stream_uploads pushes artworks as they become visible, as server-sent events.
+/// Each `upload` event carries the art id, characters and rating of one artwork
+#[get("/api/stream/uploads")]
+pub async fn api_stream_uploads(
+    upload_events: Data<UploadEvents>,
+    Query(filter): Query<UploadFilter>,
+) -> impl Responder {
+    let receiver = upload_events.subscribe();
+    let events = stream::unfold(receiver, move |mut receiver| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if filter.matches(&event) => {
+                        let frame = format!("event: upload\ndata: {}\n\n", json!(event));
+                        return Some((Bytes::from(frame), receiver));
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!(
+                            "Upload stream subscriber lagged, {} events skipped",
+                            skipped
+                        );
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    let keepalive = IntervalStream::new(tokio::time::interval(UPLOAD_STREAM_KEEPALIVE))
+        .map(|_| Bytes::from_static(b": keepalive\n\n"));
+    let body = stream::select(events, keepalive).map(Ok::<_, actix_web::Error>);
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .insert_header((http::header::CACHE_CONTROL, "no-cache"))
+        // Compression would hold events back until enough of them are buffered
+        .insert_header((http::header::CONTENT_ENCODING, "identity"))
+        .insert_header(("x-accel-buffering", "no"))
+        .streaming(body)
+}
+
+/// The json of a webhook, without its secret
+fn webhook_json(webhook: &Webhook) -> serde_json::Value {
+    json!({
+        "id": webhook.id,
+        "url": webhook.url,
+        "events": webhook.events,
+        "created_at": webhook.created_at,
+    })
+}
+
+/// api_admin_register_webhook registers a url to receive signed content change deliveries
+#[post("/api/admin/webhooks")]
+pub async fn api_admin_register_webhook(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    web::Json(info): web::Json<WebhookRequest>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    let message = match reqwest::Url::parse(info.url.trim()) {
+        Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+            Some("url must be http or https".to_owned())
+        }
+        Ok(_) if info.events.is_empty() => Some("events must not be empty".to_owned()),
+        Ok(_) if info.secret.len() < MIN_SECRET_LEN => Some(format!(
+            "secret must be at least {} characters",
+            MIN_SECRET_LEN
+        )),
+        Ok(_) => None,
+        Err(e) => Some(format!("invalid url: {}", e)),
+    };
+    if let Some(message) = message {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": message }).to_string());
+    }
+    let mut events = info.events;
+    events.sort();
+    events.dedup();
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().to_string(),
+        url: info.url.trim().to_owned(),
+        events,
+        secret: info.secret,
+        created_at: Utc::now().timestamp(),
+    };
+    match insert_webhook(&db, &webhook).await {
+        Ok(()) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "data": webhook_json(&webhook) }).to_string()),
         Err(e) => HttpResponse::InternalServerError()
-            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-            .body(e.to_string()),
+            .content_type("application/json")
+            .body(json!({ "message": e.to_string() }).to_string()),
     }
 }
 
-/// api_character_ids returns artwork ids related to a specific character
-#[get("/api/character/{name}")]
-pub async fn api_character_ids(
+/// api_admin_webhooks lists the registered webhooks
+#[get("/api/admin/webhooks")]
+pub async fn api_admin_webhooks(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    match get_webhooks(&db).await {
+        Ok(webhooks) => HttpResponse::Ok().content_type("application/json").body(
+            json!({ "data": webhooks.iter().map(webhook_json).collect::<Vec<_>>() }).to_string(),
+        ),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(json!({ "message": e.to_string() }).to_string()),
+    }
+}
+
+/// api_admin_delete_webhook unregisters a webhook. Its delivery log expires on its own
+#[delete("/api/admin/webhooks/{id}")]
+pub async fn api_admin_delete_webhook(
     db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
     params: web::Path<(String,)>,
-    Query(info): Query<ArtworkIdRequest>,
+    req: HttpRequest,
 ) -> impl Responder {
-    let (name,) = params.into_inner();
-    let art_type = info.art_type.unwrap_or_else(|| "SFW".to_owned());
-    let options = ArtworkQueryOption::builder()
-        .characters(vec![name])
-        .image_type(art_type)
-        .build();
-    match get_ids(&db, options).await {
-        Ok(id_list) => HttpResponse::Ok()
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
             .content_type("application/json")
-            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-            .body(
-                json! ({
-                    "data": id_list,
-                })
-                .to_string(),
-            ),
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    let (id,) = params.into_inner();
+    match delete_webhook(&db, &id).await {
+        Ok(true) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "message": "ok" }).to_string()),
+        Ok(false) => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json!({ "message": format!("no webhook {}", id) }).to_string()),
         Err(e) => HttpResponse::InternalServerError()
             .content_type("application/json")
-            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-            .body(json!({ "message": e.to_string()}).to_string()),
+            .body(json!({ "message": e.to_string() }).to_string()),
     }
 }
 
-/// api_image_info takes a list of ids and returns the corresponding artwork metadata
-#[get("/api/image-info")]
-pub async fn api_image_info(db: Data<Database>, req: HttpRequest) -> impl Responder {
-    // Need to explicitly parse the query string since they're arrays
-    // https://github.com/samscott89/serde_qs/blob/main/examples/introduction.rs
-    let query = req.query_string();
-    let qs = serde_qs::Config::new(usize::MAX - 64, false);
-    match qs.deserialize_str::<ArtworkInfoRequest>(query) {
-        Ok(info) => match get_artwork_info_by_ids(&db, info.ids.unwrap_or_default()).await {
-            Ok(artwork_info) => HttpResponse::Ok()
-                .content_type("application/json")
-                .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-                .body(
-                    json!({
-                        "data": artwork_info,
-                    })
-                    .to_string(),
-                ),
-            Err(e) => HttpResponse::InternalServerError()
-                .content_type("application/json")
-                .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-                .body(json!({ "message": e.to_string() }).to_string()),
-        },
-        Err(e) => HttpResponse::BadRequest()
+/// api_admin_webhook_deliveries shows the latest deliveries of a webhook with every attempt
+#[get("/api/admin/webhooks/{id}/deliveries")]
+pub async fn api_admin_webhook_deliveries(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    params: web::Path<(String,)>,
+    Query(info): Query<WebhookDeliveriesRequest>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    let (id,) = params.into_inner();
+    let limit = info.limit.unwrap_or(50).clamp(1, WEBHOOK_DELIVERIES_MAX);
+    match get_webhook_deliveries(&db, &id, limit).await {
+        Ok(deliveries) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "data": deliveries }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
             .content_type("application/json")
-            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
             .body(json!({ "message": e.to_string() }).to_string()),
     }
 }
 
-/// api_statistics tracks a few metadata on the collection level
-#[get("/api/statistics")]
-pub async fn api_statistics(db: Data<Database>) -> impl Responder {
-    let results = join! {
-        get_artwork_count_total(&db),
-        get_latest_upload_time(&db),
-        get_artwork_count_sfw(&db),
-        get_artwork_count_nsfw(&db),
-        get_artwork_count_r18(&db),
-    };
-    let total = results.0.unwrap_or(0);
-    let t = results.1.unwrap_or(0);
-    let sfw = results.2.unwrap_or(0);
-    let nsfw = results.3.unwrap_or(0);
-    let r18 = results.4.unwrap_or(0);
-    let body = json! ({
-        "data": {
-            "artwork": {
-                "total": total,
-                "sfw": sfw,
-                "nsfw": nsfw,
-                "r18": r18,
-                "latestUploadTime": t,
-            }
+/// api_admin_export streams every artwork matching the filters as newline-delimited json,
+/// one `ArtworkInfo` per line, in the form db sync accepts
+#[get("/api/admin/export")]
+pub async fn api_admin_export(
+    db: Data<Database>,
+    db_sync_token: Data<DbSyncToken>,
+    Query(info): Query<ExportRequest>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "message": err.to_string() }).to_string());
+    }
+    let filter = ExportFilter {
+        rating: info.rating,
+        status: info.status,
+        since: info.since,
+        until: info.until,
+    };
+    let artworks = match stream_artworks(&db, &filter).await {
+        Ok(artworks) => artworks,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .body(json!({ "message": e.to_string() }).to_string())
+        }
+    };
+    let body = artworks.ready_chunks(EXPORT_STREAM_CHUNK).map(|chunk| {
+        let mut buffer = Vec::new();
+        for artwork in chunk {
+            let artwork = artwork.map_err(|e| {
+                log::error!("Export cursor error {:?}", e);
+                actix_web::error::ErrorInternalServerError(e)
+            })?;
+            serde_json::to_writer(&mut buffer, &artwork)?;
+            buffer.push(b'\n');
         }
+        Ok::<_, actix_web::Error>(Bytes::from(buffer))
     });
     HttpResponse::Ok()
-        .content_type("application/json")
-        .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-        .body(body.to_string())
+        .content_type(ndjson::CONTENT_TYPE)
+        .insert_header((
+            http::header::CONTENT_DISPOSITION,
+            "attachment; filename=\"artworks.ndjson\"",
+        ))
+        .streaming(body)
 }
 
-/// api_db_sync accepts authorized updates to the db
-#[post("/api/db/sync")]
-pub async fn api_db_sync(
+/// api_admin_validate scans the artwork collection and reports documents that don't fit `ArtworkInfo`
+#[get("/api/admin/validate")]
+pub async fn api_admin_validate(
     db: Data<Database>,
     db_sync_token: Data<DbSyncToken>,
-    web::Json(artwork_list): web::Json<Vec<ArtworkInfo>>,
     req: HttpRequest,
 ) -> impl Responder {
     if let Err(err) = validate_db_sync_token(db_sync_token.token(), req.headers()) {
         return HttpResponse::BadRequest()
             .content_type("application/json")
-            .body(
-                json!({
-                    "message": err.to_string(),
-                })
-                .to_string(),
-            );
+            .body(json!({ "message": err.to_string() }).to_string());
     }
-    match save_artwork_many(&db, artwork_list).await {
-        Ok(()) => HttpResponse::Ok().content_type("application/json").body(
+    match validate_artworks(&db).await {
+        Ok((scanned, invalid)) => HttpResponse::Ok().content_type("application/json").body(
             json!({
-                "message": "ok",
+                "data": {
+                    "scanned": scanned,
+                    "invalid": invalid,
+                }
             })
             .to_string(),
         ),
-        Err(_) => HttpResponse::InternalServerError()
+        Err(e) => HttpResponse::InternalServerError()
             .content_type("application/json")
-            .body(
-                json! ({
-                    "message": "error",
-                })
-                .to_string(),
-            ),
+            .body(json!({ "message": e.to_string() }).to_string()),
     }
 }
 
-/// validate_db_sync_token checks the http authorize header against the token
+/// validate_db_sync_token checks the http authorize header against the token.
+/// Without a configured token, every request is refused
 fn validate_db_sync_token(
     db_sync_token: String,
     headers: &http::header::HeaderMap,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    if db_sync_token.is_empty() {
+        return Err("Sync token not configured".into());
+    }
     if let Some(authorization_header) = headers.get(http::header::AUTHORIZATION) {
         if let Ok(authorization_str) = from_utf8(authorization_header.as_bytes()) {
             let expected = format!("Bearer {}", db_sync_token);
@@ -225,3 +1783,282 @@ fn validate_db_sync_token(
     }
     Err("Authorization header not found".into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        accepts_media_type, api_db_sync_import, copy_ids_into_cache, count_query_ids, dedup_ids,
+        expand_characters, id_list_response, json_weight, parse_timestamp, parse_window,
+        validate_db_sync_token, ApiCache, ArtworkInfoRequest, DbSyncToken, IdListFormat,
+        ImageInfoBody, ID_STREAM_CHUNK, TOO_MANY_IDS,
+    };
+    use crate::db::ArtworkQueryOption;
+    use crate::delta;
+    use crate::events::UploadEvents;
+    use crate::webhooks::{RetryPolicy, Webhooks};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::web::Data;
+    use actix_web::{body, http, App};
+    use futures::stream::{self, StreamExt};
+    use serde::de::DeserializeSeed;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_id_list_response_is_valid_json() {
+        let count = ID_STREAM_CHUNK as i64 * 2 + 1;
+        let res = id_list_response(
+            Box::pin(stream::iter((0..count).map(Ok))),
+            IdListFormat::Json,
+        );
+        let bytes = body::to_bytes(res.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let ids: Vec<i64> = serde_json::from_value(value["data"].clone()).unwrap();
+        assert_eq!(ids, (0..count).collect::<Vec<i64>>());
+
+        let res = id_list_response(Box::pin(stream::empty()), IdListFormat::Json);
+        let bytes = body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&bytes[..], b"{\"data\":[]}");
+
+        let res = id_list_response(
+            Box::pin(stream::iter((0..count).map(Ok))),
+            IdListFormat::Delta,
+        );
+        let bytes = body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            delta::decode(&bytes).unwrap(),
+            (0..count).collect::<Vec<i64>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_ids_into_cache_up_to_max_bytes() {
+        let cache = Data::new(ApiCache::new(
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            10,
+            3 * std::mem::size_of::<i64>(),
+        ));
+        let copy = |limit: u64, count: i64| {
+            let options = ArtworkQueryOption::builder().limit(limit).build();
+            let ids = Box::pin(stream::iter((0..count).map(Ok)));
+            let generation = cache.ids.generation();
+            copy_ids_into_cache(ids, cache.clone(), options, generation)
+        };
+        let ids: Vec<i64> = copy(3, 3).map(Result::unwrap).collect().await;
+        assert_eq!(ids, vec![0, 1, 2]);
+        let ids: Vec<i64> = copy(4, 4).map(Result::unwrap).collect().await;
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+        let cached = |limit: u64| {
+            let options = ArtworkQueryOption::builder().limit(limit).build();
+            cache.ids.get(&options)
+        };
+        assert_eq!(cached(3).as_deref(), Some(&vec![0, 1, 2]));
+        assert_eq!(cached(4), None);
+    }
+
+    #[test]
+    fn test_accepts_media_type() {
+        let octet_stream = "application/octet-stream";
+        assert!(accepts_media_type("application/octet-stream", octet_stream));
+        assert!(accepts_media_type(
+            "application/json;q=0.5, Application/Octet-Stream",
+            octet_stream
+        ));
+        assert!(!accepts_media_type("*/*", octet_stream));
+        assert!(!accepts_media_type(
+            "application/octet-stream;q=0",
+            octet_stream
+        ));
+    }
+
+    #[test]
+    fn test_expand_characters() {
+        let characters = vec!["ayaka".to_owned()];
+        assert_eq!(
+            expand_characters(characters.clone(), None, None, None),
+            Some(characters.clone())
+        );
+        assert_eq!(
+            expand_characters(characters.clone(), Some("Cryo"), None, None),
+            Some(vec!["^KamisatoAyaka$".to_owned(), "^Ayaka$".to_owned()])
+        );
+        assert_eq!(
+            expand_characters(characters, Some("Pyro"), None, None),
+            None
+        );
+        assert_eq!(
+            expand_characters(vec![], Some("Geo"), Some("Inazuma"), None)
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1646752321"), Some(1646752321));
+        assert_eq!(parse_timestamp("2022-03-08"), Some(1646697600));
+        assert_eq!(parse_timestamp("2022-03-08T15:12:01Z"), Some(1646752321));
+        assert_eq!(
+            parse_timestamp("2022-03-09T00:12:01+09:00"),
+            Some(1646752321)
+        );
+        assert_eq!(parse_timestamp("2022-03-08T15:12:01"), Some(1646752321));
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("24h"), Some(24 * 60 * 60));
+        assert_eq!(parse_window("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_window("0h"), None);
+        assert_eq!(parse_window("h"), None);
+        assert_eq!(parse_window("1w"), None);
+    }
+
+    #[test]
+    fn test_dedup_ids_keeps_first_occurrence() {
+        assert_eq!(dedup_ids(vec![3, 1, 3, 2, 1]), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn test_image_info_query_parses_many_ids() {
+        let qs = serde_qs::Config::new(2, false);
+        let query = (0..500)
+            .map(|i| format!("ids[]={}", i))
+            .collect::<Vec<_>>()
+            .join("&");
+        let info: ArtworkInfoRequest = qs.deserialize_str(&query).unwrap();
+        assert_eq!(info.ids.unwrap().len(), 500);
+        let info: ArtworkInfoRequest = qs.deserialize_str("ids[0]=1&ids[1]=2").unwrap();
+        assert_eq!(info.ids.unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_count_query_ids() {
+        assert_eq!(count_query_ids(""), 0);
+        assert_eq!(
+            count_query_ids("ids[]=1&ids%5B%5D=2&ids[2]=3&fields=thumb"),
+            3
+        );
+        assert_eq!(count_query_ids("ids=1&idsx=2"), 1);
+    }
+
+    #[test]
+    fn test_count_query_ids_decodes_keys() {
+        let query = "%69ds[]=1&i%64s%5B%5D=2&%69%64%73[]=3";
+        assert_eq!(count_query_ids(query), 3);
+        let qs = serde_qs::Config::new(2, false);
+        let info: ArtworkInfoRequest = qs.deserialize_str(query).unwrap();
+        assert_eq!(info.ids.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_image_info_body_bounds_ids() {
+        let parse = |body: &str| {
+            let mut deserializer = serde_json::Deserializer::from_slice(body.as_bytes());
+            ImageInfoBody { max_ids: 2 }.deserialize(&mut deserializer)
+        };
+        let info = parse(r#"{"ids": [1, 2], "fields": "thumb", "other": {}}"#).unwrap();
+        assert_eq!(info.ids, Some(vec![1, 2]));
+        assert_eq!(info.fields.as_deref(), Some("thumb"));
+        assert_eq!(parse(r#"{"ids": null}"#).unwrap().ids, None);
+        assert_eq!(parse("{}").unwrap().ids, None);
+        let error = parse(r#"{"ids": [1, 2, 3, "not even read"]}"#)
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with(TOO_MANY_IDS));
+        assert!(parse(r#"{"ids": ["1"]}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_db_sync_token() {
+        let mut headers = http::header::HeaderMap::new();
+        assert!(validate_db_sync_token("t".to_owned(), &headers).is_err());
+        headers.insert(
+            http::header::AUTHORIZATION,
+            http::header::HeaderValue::from_static("Bearer "),
+        );
+        assert!(validate_db_sync_token("".to_owned(), &headers).is_err());
+        assert!(validate_db_sync_token("t".to_owned(), &headers).is_err());
+        headers.insert(
+            http::header::AUTHORIZATION,
+            http::header::HeaderValue::from_static("Bearer t"),
+        );
+        assert!(validate_db_sync_token("t".to_owned(), &headers).is_ok());
+    }
+
+    fn import_request(token: &str, body: &'static str) -> TestRequest {
+        TestRequest::post()
+            .uri("/api/db/sync?announce=false")
+            .insert_header((http::header::CONTENT_TYPE, "application/x-ndjson"))
+            .insert_header((http::header::AUTHORIZATION, format!("Bearer {}", token)))
+            .set_payload(body)
+    }
+
+    #[tokio::test]
+    async fn test_import_summarizes_failed_lines() {
+        // The database can't be reached, so no line is saved
+        let db =
+            mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+                .await
+                .unwrap()
+                .database("pixiv");
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+        };
+        let service = init_service(
+            App::new()
+                .app_data(Data::new(db))
+                .app_data(Data::new(DbSyncToken::new("t".to_owned())))
+                .app_data(Data::new(ApiCache::new(
+                    Duration::from_secs(1),
+                    Duration::from_secs(1),
+                    1,
+                    1 << 20,
+                )))
+                .app_data(Data::new(UploadEvents::new(1)))
+                .app_data(Data::new(Webhooks::new(policy).unwrap()))
+                .service(api_db_sync_import),
+        )
+        .await;
+
+        let res = call_service(&service, import_request("x", "{}\n").to_request()).await;
+        assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["message"], "Invalid token");
+
+        let body = "{oops\n\n{\"art_id\": 5}\n{\"art_id\": 96664758, \"title\": \"\", \"tag_str\": \"\", \"characters\": [], \"view_count\": 0, \"like_count\": 0, \"love_count\": 0, \"artist_id\": 1, \"upload_timestamp\": 0}";
+        let res = call_service(&service, import_request("t", body).to_request()).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let body: serde_json::Value = read_body_json(res).await;
+        let summary = &body["data"];
+        assert_eq!(summary["lines"], 4);
+        assert_eq!(summary["saved"], 0);
+        assert_eq!(summary["failed"], 3);
+        let errors = summary["errors"].as_array().unwrap();
+        assert_eq!(errors[0]["line"], 1);
+        assert_eq!(errors[0]["art_id"], serde_json::Value::Null);
+        assert_eq!(errors[1]["line"], 3);
+        assert_eq!(errors[1]["art_id"], 5);
+        assert_eq!(errors[2]["line"], 4);
+        assert_eq!(errors[2]["art_id"], 96664758);
+        assert!(errors[2]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("failed to save artwork 96664758"));
+    }
+
+    #[test]
+    fn test_json_weight_is_close_to_serialized_size() {
+        let value = serde_json::json!({
+            "data": [{ "art_id": 96664758, "title": "Ganyu", "nsfw": false, "tags": null }],
+        });
+        let serialized = value.to_string().len();
+        let weight = json_weight(&value);
+        assert!(weight >= serialized / 2 && weight <= serialized * 2);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::metrics;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// TtlCache keeps values for a fixed time after they're inserted, holding at most `capacity`
+/// entries, and with `weighted`, at most `max_bytes` of them. It is shared between workers,
+/// so entries are cloned out on lookup
+pub struct TtlCache<K, V> {
+    /// Label of the cache in the `cache_lookups_total` metric
+    name: &'static str,
+    ttl: Duration,
+    capacity: usize,
+    max_bytes: usize,
+    /// Approximate size of a value in bytes
+    weigh: fn(&V) -> usize,
+    /// Bumped by `invalidate`, so that values computed before it are not stored after it
+    generation: AtomicU64,
+    /// Values with the time they were inserted and their weight
+    entries: Mutex<HashMap<K, (Instant, usize, V)>>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(name: &'static str, ttl: Duration, capacity: usize) -> Self {
+        TtlCache {
+            name,
+            ttl,
+            capacity,
+            max_bytes: usize::MAX,
+            weigh: |_| 0,
+            generation: AtomicU64::new(0),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Also bounds the cache by the total weight of its values. Values heavier than
+    /// `max_bytes` on their own are never stored
+    pub fn weighted(mut self, max_bytes: usize, weigh: fn(&V) -> usize) -> Self {
+        self.max_bytes = max_bytes;
+        self.weigh = weigh;
+        self
+    }
+
+    /// Most bytes of values held at once, `usize::MAX` unless `weighted`
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Returns the value of a key, unless it is missing or expired
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        let value = match entries.get(key) {
+            Some((inserted_at, _, value)) if inserted_at.elapsed() < self.ttl => {
+                Some(value.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        metrics::record_cache_lookup(self.name, value.is_some());
+        value
+    }
+
+    /// Stores a value, replacing any previous value of the key.
+    /// Expired entries are dropped first, then the oldest ones while the cache is full
+    pub fn insert(&self, key: K, value: V) {
+        let weight = (self.weigh)(&value);
+        if self.capacity == 0 || weight > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, (inserted_at, _, _)| inserted_at.elapsed() < ttl);
+        entries.remove(&key);
+        let mut bytes: usize = entries.values().map(|(_, weight, _)| weight).sum();
+        while entries.len() >= self.capacity || bytes + weight > self.max_bytes {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (inserted_at, _, _))| *inserted_at)
+                .map(|(key, _)| key.clone());
+            match oldest.and_then(|oldest| entries.remove(&oldest)) {
+                Some((_, oldest_weight, _)) => bytes -= oldest_weight,
+                None => break,
+            };
+        }
+        entries.insert(key, (Instant::now(), weight, value));
+    }
+
+    /// Returns the cached value of a key, or computes and stores it.
+    /// Values computed while the cache was invalidated are returned but not stored
+    pub async fn get_or_try_insert_with<F, Fut, E>(&self, key: K, compute: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+        let generation = self.generation();
+        let value = compute().await?;
+        self.insert_unless_invalidated(key, value.clone(), generation);
+        Ok(value)
+    }
+
+    /// Current generation, to compute a value outside of `get_or_try_insert_with`
+    /// and store it with `insert_unless_invalidated`
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Stores a value computed since `generation` was read, unless the cache was
+    /// invalidated meanwhile
+    pub fn insert_unless_invalidated(&self, key: K, value: V, generation: u64) {
+        if self.generation() == generation {
+            self.insert(key, value);
+        }
+    }
+
+    /// Drops every entry, e.g. after the underlying data changed
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TtlCache;
+    use std::time::Duration;
+
+    #[test]
+    fn test_get_returns_fresh_entries() {
+        let cache = TtlCache::new("test", Duration::from_secs(60), 10);
+        cache.insert("ganyu", 1);
+        assert_eq!(cache.get(&"ganyu"), Some(1));
+        assert_eq!(cache.get(&"keqing"), None);
+    }
+
+    #[test]
+    fn test_get_drops_expired_entries() {
+        let cache = TtlCache::new("test", Duration::from_secs(0), 10);
+        cache.insert("ganyu", 1);
+        assert_eq!(cache.get(&"ganyu"), None);
+    }
+
+    #[test]
+    fn test_insert_evicts_oldest_when_full() {
+        let cache = TtlCache::new("test", Duration::from_secs(60), 2);
+        cache.insert("ganyu", 1);
+        cache.insert("keqing", 2);
+        cache.insert("ganyu", 3);
+        cache.insert("xiao", 4);
+        assert_eq!(cache.get(&"keqing"), None);
+        assert_eq!(cache.get(&"ganyu"), Some(3));
+        assert_eq!(cache.get(&"xiao"), Some(4));
+    }
+
+    #[test]
+    fn test_weighted_cache_bounds_bytes() {
+        let cache = TtlCache::new("test", Duration::from_secs(60), 10)
+            .weighted(10, |value: &String| value.len());
+        cache.insert("ganyu", "1234".to_owned());
+        cache.insert("keqing", "1234".to_owned());
+        cache.insert("xiao", "12345".to_owned());
+        assert_eq!(cache.get(&"ganyu"), None);
+        assert_eq!(cache.get(&"keqing"), Some("1234".to_owned()));
+        assert_eq!(cache.get(&"xiao"), Some("12345".to_owned()));
+        cache.insert("zhongli", "12345678901".to_owned());
+        assert_eq!(cache.get(&"zhongli"), None);
+        assert_eq!(cache.get(&"xiao"), Some("12345".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_skips_values_computed_before_it() {
+        let cache = TtlCache::new("test", Duration::from_secs(60), 10);
+        let value: Result<i32, ()> = cache
+            .get_or_try_insert_with("ganyu", || async {
+                cache.invalidate();
+                Ok(1)
+            })
+            .await;
+        assert_eq!(value, Ok(1));
+        assert_eq!(cache.get(&"ganyu"), None);
+    }
+}
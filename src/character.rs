@@ -0,0 +1,131 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// Metadata of a playable character, as listed in `data/characters.json`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CharacterMeta {
+    /// The name used in `ArtworkInfo.characters`
+    pub name: String,
+    /// Other names the character may be stored under
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    pub element: Option<String>,
+    pub weapon: Option<String>,
+    pub region: Option<String>,
+    /// 4 or 5 stars
+    pub rarity: Option<u8>,
+}
+
+impl CharacterMeta {
+    /// Checks whether a stored character name refers to this character, ignoring case
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim();
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+
+    /// Checks whether a (partial) name, as accepted by the `character` filter,
+    /// refers to this character, ignoring case
+    pub fn matches_partial(&self, partial: &str) -> bool {
+        let partial = partial.trim().to_lowercase();
+        std::iter::once(&self.name)
+            .chain(self.aliases.iter())
+            .any(|name| name.to_lowercase().contains(&partial))
+    }
+
+    /// Regex patterns matching exactly the names this character may be stored under
+    pub fn name_patterns(&self) -> Vec<String> {
+        std::iter::once(&self.name)
+            .chain(self.aliases.iter())
+            .map(|name| format!("^{}$", escape_regex(name)))
+            .collect()
+    }
+}
+
+/// Escapes regex metacharacters so that a name is matched literally
+pub(crate) fn escape_regex(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Character catalog bundled with the binary
+static CATALOG: Lazy<Vec<CharacterMeta>> = Lazy::new(|| {
+    serde_json::from_str(include_str!("../data/characters.json"))
+        .expect("data/characters.json is a valid character catalog")
+});
+
+/// Every character in the bundled catalog
+pub fn catalog() -> &'static [CharacterMeta] {
+    &CATALOG
+}
+
+/// Looks up the metadata of a character by name or alias
+pub fn find(name: &str) -> Option<&'static CharacterMeta> {
+    catalog().iter().find(|meta| meta.matches(name))
+}
+
+/// Finds the characters whose metadata matches every given value, ignoring case
+pub fn filter(
+    element: Option<&str>,
+    region: Option<&str>,
+    weapon: Option<&str>,
+) -> Vec<&'static CharacterMeta> {
+    let is_match = |expected: Option<&str>, actual: &Option<String>| match expected {
+        Some(expected) => actual
+            .as_deref()
+            .map(|actual| actual.eq_ignore_ascii_case(expected.trim()))
+            .unwrap_or(false),
+        None => true,
+    };
+    catalog()
+        .iter()
+        .filter(|meta| {
+            is_match(element, &meta.element)
+                && is_match(region, &meta.region)
+                && is_match(weapon, &meta.weapon)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{catalog, filter, find};
+
+    #[test]
+    fn test_filter_by_metadata() {
+        let inazuma_pyro = filter(Some("pyro"), Some("Inazuma"), None);
+        let names: Vec<&str> = inazuma_pyro.iter().map(|meta| meta.name.as_str()).collect();
+        assert_eq!(names, vec!["Thoma", "Yoimiya"]);
+        assert!(filter(Some("Dendro"), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_name_patterns() {
+        assert_eq!(
+            find("Ayaka").unwrap().name_patterns(),
+            vec!["^KamisatoAyaka$", "^Ayaka$"]
+        );
+        assert!(find("KamisatoAyaka").unwrap().matches_partial("ayaka"));
+    }
+
+    #[test]
+    fn test_catalog_loads() {
+        assert!(!catalog().is_empty());
+    }
+
+    #[test]
+    fn test_find_by_name_or_alias() {
+        assert_eq!(find("raidenshogun").unwrap().name, "RaidenShogun");
+        assert_eq!(find("Ayaka").unwrap().name, "KamisatoAyaka");
+        assert!(find("Paimon").is_none());
+    }
+}
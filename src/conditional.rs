@@ -0,0 +1,321 @@
+use crate::api::ApiCache;
+use crate::cache::TtlCache;
+use crate::db::{get_write_version, seed_hash, WriteVersion};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{
+    self, EntityTag, Header, HeaderName, HeaderValue, HttpDate, IfModifiedSince, IfNoneMatch,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+use chrono::{TimeZone, Utc};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use mongodb::Database;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `Cache-Control` of the read endpoints unless configured otherwise:
+/// responses may be stored but must be revalidated, which is cheap with the validators
+pub const DEFAULT_CACHE_CONTROL: &str = "no-cache";
+
+/// How long the write version is reused before it is read again, unless configured otherwise
+pub const DEFAULT_WRITE_VERSION_TTL: Duration = Duration::from_secs(1);
+
+/// Request headers the representation depends on, e.g. id listing format and compression
+const VARY_HEADERS: &[&str] = &["accept", "accept-encoding"];
+
+/// Routes (as matched patterns) whose responses only change on db sync
+const DEFAULT_ROUTES: &[&str] = &[
+    "/api/characters",
+    "/api/character/{name}",
+    "/api/character/{name}/related",
+    "/api/character-list",
+    "/api/image-info",
+    "/api/statistics",
+];
+
+/// ConditionalGet middleware adds `ETag`, `Last-Modified` and `Cache-Control` headers to
+/// GET responses of the configured routes, and answers matching
+/// `If-None-Match`/`If-Modified-Since` requests with 304 without running the handler.
+///
+/// The validators derive from the write version bumped on every db sync, the request uri,
+/// the `VARY_HEADERS` and the current date (statistics cover the last days, so they change daily too).
+/// Requests with an `Authorization` header are left alone since their body may differ.
+/// The write version is read from the database at most once per `version_ttl`, so
+/// validators may lag a db sync by that long
+#[derive(Clone)]
+pub struct ConditionalGet {
+    /// Route pattern to `Cache-Control` value
+    routes: Arc<HashMap<String, String>>,
+    /// The write version last read, shared by every worker
+    version: Arc<TtlCache<(), WriteVersion>>,
+}
+
+impl ConditionalGet {
+    pub fn new(routes: HashMap<String, String>, version_ttl: Duration) -> Self {
+        ConditionalGet {
+            routes: Arc::new(routes),
+            version: Arc::new(TtlCache::new("write_version", version_ttl, 1)),
+        }
+    }
+
+    /// The default routes with `DEFAULT_CACHE_CONTROL`, overridden by `CACHE_CONTROL`,
+    /// e.g. `/api/statistics=public, max-age=60;/api/character-list=public, max-age=300`.
+    /// The write version is reused for `WRITE_VERSION_TTL_MS` milliseconds
+    pub fn from_env() -> Self {
+        let mut routes: HashMap<String, String> = DEFAULT_ROUTES
+            .iter()
+            .map(|route| (route.to_string(), DEFAULT_CACHE_CONTROL.to_owned()))
+            .collect();
+        if let Ok(value) = std::env::var("CACHE_CONTROL") {
+            routes.extend(parse_cache_control_config(&value));
+        }
+        let version_ttl = std::env::var("WRITE_VERSION_TTL_MS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_WRITE_VERSION_TTL);
+        ConditionalGet::new(routes, version_ttl)
+    }
+}
+
+/// Parses `route=cache control;route=cache control` pairs, skipping malformed ones
+fn parse_cache_control_config(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|pair| {
+            let (route, cache_control) = pair.split_once('=')?;
+            let (route, cache_control) = (route.trim(), cache_control.trim());
+            if route.is_empty() || cache_control.is_empty() {
+                log::warn!("Ignore malformed CACHE_CONTROL entry {:?}", pair);
+                return None;
+            }
+            Some((route.to_owned(), cache_control.to_owned()))
+        })
+        .collect()
+}
+
+/// Validators of a response
+struct Validators {
+    etag: EntityTag,
+    last_modified: SystemTime,
+}
+
+impl Validators {
+    /// `variant` identifies the representation, e.g. the uri and accepted encodings
+    fn new(version: &WriteVersion, variant: &str) -> Self {
+        let today = Utc::now().naive_utc().date();
+        let etag = format!(
+            "{:016x}",
+            seed_hash(&format!(
+                "{}:{}:{}:{}",
+                version.generation, version.updated_at, today, variant
+            ))
+        );
+        // The date is part of the etag, so the response is as new as the day at least
+        let start_of_day = Utc
+            .from_utc_datetime(&today.and_hms_opt(0, 0, 0).expect("midnight is valid"))
+            .timestamp();
+        let last_modified = version.updated_at.max(start_of_day).max(0) as u64;
+        Validators {
+            etag: EntityTag::new_strong(etag),
+            last_modified: UNIX_EPOCH + Duration::from_secs(last_modified),
+        }
+    }
+
+    /// Checks whether the client's copy is still current.
+    /// `If-Modified-Since` is only considered without `If-None-Match`
+    fn is_fresh(
+        &self,
+        if_none_match: Option<IfNoneMatch>,
+        if_modified_since: Option<IfModifiedSince>,
+    ) -> bool {
+        match (if_none_match, if_modified_since) {
+            (Some(IfNoneMatch::Any), _) => true,
+            (Some(IfNoneMatch::Items(tags)), _) => tags.iter().any(|tag| tag.weak_eq(&self.etag)),
+            (None, Some(IfModifiedSince(since))) => self.last_modified <= SystemTime::from(since),
+            (None, None) => false,
+        }
+    }
+
+    /// Headers of both full and 304 responses. `Vary` supersedes the one set by `Compress`,
+    /// which is why this middleware wraps it
+    fn headers(&self, cache_control: &str) -> Vec<(HeaderName, HeaderValue)> {
+        [
+            (header::ETAG, self.etag.to_string()),
+            (
+                header::LAST_MODIFIED,
+                HttpDate::from(self.last_modified).to_string(),
+            ),
+            (header::CACHE_CONTROL, cache_control.to_owned()),
+            (header::VARY, VARY_HEADERS.join(", ")),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, HeaderValue::from_str(&value).ok()?)))
+        .collect()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ConditionalGet
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ConditionalGetMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ConditionalGetMiddleware {
+            service: Rc::new(service),
+            routes: self.routes.clone(),
+            version: self.version.clone(),
+        }))
+    }
+}
+
+pub struct ConditionalGetMiddleware<S> {
+    service: Rc<S>,
+    routes: Arc<HashMap<String, String>>,
+    version: Arc<TtlCache<(), WriteVersion>>,
+}
+
+impl<S, B> Service<ServiceRequest> for ConditionalGetMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let cache_control =
+            if req.method() == Method::GET && !req.headers().contains_key(header::AUTHORIZATION) {
+                req.match_pattern()
+                    .and_then(|route| self.routes.get(&route).cloned())
+            } else {
+                None
+            };
+        let db = req.app_data::<Data<Database>>().cloned();
+        let service = self.service.clone();
+        let cached_version = self.version.clone();
+        Box::pin(async move {
+            let (cache_control, db) = match (cache_control, db) {
+                (Some(cache_control), Some(db)) => (cache_control, db),
+                _ => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+            let version = match cached_version
+                .get_or_try_insert_with((), || get_write_version(&db))
+                .await
+            {
+                Ok(version) => version,
+                Err(e) => {
+                    log::warn!("Get write version {:?}", e);
+                    return Ok(service.call(req).await?.map_into_left_body());
+                }
+            };
+            // Otherwise cached bodies could be served with the validators of newer data
+            if let Some(cache) = req.app_data::<Data<ApiCache>>() {
+                cache.observe_write_generation(version.generation);
+            }
+            // Responses differ by format and encoding, so do their etags
+            let variant = std::iter::once(req.uri().to_string())
+                .chain(VARY_HEADERS.iter().map(|name| {
+                    req.headers()
+                        .get(*name)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default()
+                        .to_owned()
+                }))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let validators = Validators::new(&version, &variant);
+            let if_none_match = if req.headers().contains_key(header::IF_NONE_MATCH) {
+                IfNoneMatch::parse(&req).ok()
+            } else {
+                None
+            };
+            let if_modified_since = if req.headers().contains_key(header::IF_MODIFIED_SINCE) {
+                IfModifiedSince::parse(&req).ok()
+            } else {
+                None
+            };
+            if validators.is_fresh(if_none_match, if_modified_since) {
+                let mut builder = HttpResponse::NotModified();
+                builder.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"));
+                for header in validators.headers(&cache_control) {
+                    builder.insert_header(header);
+                }
+                return Ok(req.into_response(builder.finish()).map_into_right_body());
+            }
+            let mut res = service.call(req).await?;
+            if res.status() == StatusCode::OK {
+                for (name, value) in validators.headers(&cache_control) {
+                    res.headers_mut().insert(name, value);
+                }
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cache_control_config, Validators};
+    use crate::db::WriteVersion;
+    use actix_web::http::header::{EntityTag, HttpDate, IfModifiedSince, IfNoneMatch};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_validators_change_with_version_and_uri() {
+        let version = WriteVersion {
+            generation: 3,
+            updated_at: 1646697600,
+        };
+        let etag = Validators::new(&version, "/api/statistics").etag;
+        assert_eq!(etag, Validators::new(&version, "/api/statistics").etag);
+        assert_ne!(etag, Validators::new(&version, "/api/characters").etag);
+        let next = WriteVersion {
+            generation: 4,
+            ..version
+        };
+        assert_ne!(etag, Validators::new(&next, "/api/statistics").etag);
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let validators = Validators::new(&WriteVersion::default(), "/api/statistics");
+        let etag = validators.etag.clone();
+        let other = EntityTag::new_strong("other".to_owned());
+        assert!(validators.is_fresh(Some(IfNoneMatch::Items(vec![other.clone(), etag])), None));
+        assert!(validators.is_fresh(Some(IfNoneMatch::Any), None));
+        assert!(!validators.is_fresh(Some(IfNoneMatch::Items(vec![other])), None));
+
+        let later = HttpDate::from(SystemTime::now() + Duration::from_secs(60));
+        let earlier = HttpDate::from(SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60));
+        assert!(validators.is_fresh(None, Some(IfModifiedSince(later))));
+        assert!(!validators.is_fresh(None, Some(IfModifiedSince(earlier))));
+        assert!(!validators.is_fresh(None, None));
+    }
+
+    #[test]
+    fn test_parse_cache_control_config() {
+        assert_eq!(
+            parse_cache_control_config("/api/statistics=public, max-age=60;bogus; =x"),
+            vec![(
+                "/api/statistics".to_owned(),
+                "public, max-age=60".to_owned()
+            )]
+        );
+    }
+}
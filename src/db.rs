@@ -1,10 +1,19 @@
 use crate::artwork::ArtworkInfo;
+use crate::featured;
+use crate::metrics;
+use crate::webhooks::ContentEvent;
 use futures::future::join_all;
-use mongodb::bson::{doc, Document};
-use mongodb::options::{ClientOptions, CreateCollectionOptions, IndexOptions, ReplaceOptions};
-use mongodb::{bson, Client, Database, IndexModel};
+use futures::stream::BoxStream;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{
+    AggregateOptions, ClientOptions, CreateCollectionOptions, FindOneAndUpdateOptions, FindOptions,
+    IndexOptions, ReturnDocument, UpdateOptions,
+};
+use mongodb::{bson, Client, Cursor, Database, IndexModel};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::join;
 use tokio_stream::StreamExt;
 use typed_builder::TypedBuilder;
@@ -12,6 +21,7 @@ use typed_builder::TypedBuilder;
 /// Creates a mongodb client object from a connection string.
 /// The connection string is preferably provided at runtime via environment variable
 pub async fn create_client(conn_str: &str) -> Result<Client, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("create_client");
     let client_options = ClientOptions::parse(conn_str).await?;
     let client = Client::with_options(client_options)?;
     Ok(client)
@@ -19,6 +29,7 @@ pub async fn create_client(conn_str: &str) -> Result<Client, Box<dyn std::error:
 
 /// Create views to simplify queries
 pub async fn create_views(db: &Database) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("create_views");
     let collection_name = "artworks";
     let _ = join! {
         db.create_collection(
@@ -75,6 +86,7 @@ pub async fn create_views(db: &Database) -> Result<(), Box<dyn std::error::Error
 
 /// Create indexes to enforce constraints and speed up queries
 pub async fn create_indexes(db: &Database) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("create_indexes");
     let collection = db.collection::<()>("artworks");
     collection
         .create_indexes(
@@ -105,6 +117,93 @@ pub async fn create_indexes(db: &Database) -> Result<(), Box<dyn std::error::Err
                         "moderate.status": 1,
                     })
                     .build(),
+                IndexModel::builder()
+                    .keys(doc! {
+                        "upload_timestamp": -1,
+                        "art_id": -1,
+                    })
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {
+                        "like_count": -1,
+                        "art_id": -1,
+                    })
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {
+                        "love_count": -1,
+                        "art_id": -1,
+                    })
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {
+                        "view_count": -1,
+                        "art_id": -1,
+                    })
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {
+                        "change_seq": 1,
+                    })
+                    .build(),
+            ],
+            None,
+        )
+        .await?;
+    db.collection::<()>(FEATURED_COLLECTION)
+        .create_indexes(
+            vec![IndexModel::builder()
+                .keys(doc! {
+                    "date": 1,
+                })
+                .options(IndexOptions::builder().unique(true).build())
+                .build()],
+            None,
+        )
+        .await?;
+    db.collection::<()>(SNAPSHOT_COLLECTION)
+        .create_indexes(
+            vec![
+                IndexModel::builder()
+                    .keys(doc! {
+                        "art_id": 1,
+                        "bucket": 1,
+                    })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {
+                        "taken_at": 1,
+                    })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(Duration::from_secs(SNAPSHOT_RETENTION_SECS))
+                            .build(),
+                    )
+                    .build(),
+            ],
+            None,
+        )
+        .await?;
+    db.collection::<()>(WEBHOOK_DELIVERIES_COLLECTION)
+        .create_indexes(
+            vec![
+                IndexModel::builder()
+                    .keys(doc! {
+                        "webhook_id": 1,
+                        "created_at": -1,
+                    })
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {
+                        "logged_at": 1,
+                    })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(Duration::from_secs(WEBHOOK_DELIVERY_RETENTION_SECS))
+                            .build(),
+                    )
+                    .build(),
             ],
             None,
         )
@@ -112,12 +211,150 @@ pub async fn create_indexes(db: &Database) -> Result<(), Box<dyn std::error::Err
     Ok(())
 }
 
+/// Collection of periodic engagement snapshots, see `record_engagement_snapshot`
+const SNAPSHOT_COLLECTION: &str = "artwork_snapshots";
+
+/// An artwork keeps at most one snapshot (the latest counters) per interval
+const SNAPSHOT_INTERVAL_SECS: i64 = 60 * 60;
+
+/// Snapshots expire after the longest trending window plus a day
+const SNAPSHOT_RETENTION_SECS: u64 = 8 * 24 * 60 * 60;
+
+/// Longest window accepted by `get_trending`
+pub const TRENDING_MAX_WINDOW_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Collection of featured artwork date slots, see `FeaturedSlot`
+const FEATURED_COLLECTION: &str = "featured";
+
+/// Collection of bookkeeping documents, such as the write version
+const META_COLLECTION: &str = "meta";
+
+/// `_id` of the write version document in `META_COLLECTION`
+const WRITE_VERSION_ID: &str = "write_version";
+
+/// `_id` of the change sequence counter in `META_COLLECTION`
+const CHANGE_SEQUENCE_ID: &str = "change_sequence";
+
+/// Reservations of change numbers older than this are taken as abandoned by a crashed writer
+const CHANGE_RESERVATION_EXPIRY_MS: i64 = 15 * 60 * 1000;
+
+/// Artworks stamped at once when backfilling change numbers
+const CHANGE_BACKFILL_CHUNK: i64 = 1000;
+
+/// Error codes of deployments without change streams: standalone servers, and servers
+/// that don't know the `$changeStream` stage
+const CHANGE_STREAMS_UNSUPPORTED: &[i32] = &[40573, 40324];
+
+/// Longest time a read of a change stream waits for new changes
+const CHANGE_STREAM_AWAIT: Duration = Duration::from_secs(10);
+
+/// Fields stamped by the artwork writes for the changes feed
+const CHANGE_FIELDS: &[&str] = &["change_seq", "change_kind", "changed_at"];
+
+/// Collection of registered webhooks, see `Webhook`
+const WEBHOOKS_COLLECTION: &str = "webhooks";
+
+/// Collection of the webhook delivery log, see `WebhookDelivery`
+const WEBHOOK_DELIVERIES_COLLECTION: &str = "webhook_deliveries";
+
+/// Delivery log entries expire after 30 days
+const WEBHOOK_DELIVERY_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Order of artwork id listings
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtworkSort {
+    Newest,
+    Oldest,
+    Likes,
+    Loves,
+    Views,
+    /// Shuffled by the listing's seed, so that its pages don't overlap
+    Random,
+}
+
+impl ArtworkSort {
+    /// Pipeline stages producing the order, with `art_id` as a stable tiebreaker.
+    /// `seed` only applies to `ArtworkSort::Random`
+    fn stages(&self, seed: &str) -> Vec<Document> {
+        let sort = match self {
+            ArtworkSort::Newest => doc! { "upload_timestamp": -1, "art_id": -1 },
+            ArtworkSort::Oldest => doc! { "upload_timestamp": 1, "art_id": 1 },
+            ArtworkSort::Likes => doc! { "like_count": -1, "art_id": -1 },
+            ArtworkSort::Loves => doc! { "love_count": -1, "art_id": -1 },
+            ArtworkSort::Views => doc! { "view_count": -1, "art_id": -1 },
+            ArtworkSort::Random => return seeded_order_stages(seed),
+        };
+        vec![doc! { "$sort": sort }]
+    }
+}
+
 /// Parses the common query options
-#[derive(Clone, Debug, Deserialize, TypedBuilder, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, TypedBuilder, Serialize)]
 #[builder(field_defaults(default, setter(strip_option)))]
 pub struct ArtworkQueryOption {
     pub characters: Option<Vec<String>>,
     pub image_type: Option<String>,
+    /// Defaults to `ArtworkSort::Newest`
+    pub sort: Option<ArtworkSort>,
+    /// Number of ids to skip, applied after sorting
+    pub offset: Option<u64>,
+    /// Most ids to return, applied after `offset`
+    pub limit: Option<u64>,
+    /// Only artworks uploaded at or after this unix timestamp (seconds)
+    pub since: Option<i64>,
+    /// Only artworks uploaded before this unix timestamp (seconds)
+    pub until: Option<i64>,
+    /// Only artworks with at least this many likes
+    pub min_likes: Option<i32>,
+    /// Only artworks with at least this many loves
+    pub min_loves: Option<i32>,
+    /// Only artworks with at least this many views
+    pub min_views: Option<i32>,
+    /// Seed of `ArtworkSort::Random`, today's date (UTC) unless given
+    pub seed: Option<String>,
+}
+
+/// Seed of random listings without one, so that their order holds for the day
+fn default_random_seed() -> String {
+    featured::today().format(featured::DATE_FORMAT).to_string()
+}
+
+impl ArtworkQueryOption {
+    /// Returns equivalent options in a canonical form, so that they can be used as a cache key.
+    /// Character names are trimmed, sorted and deduplicated, and the artwork type is uppercased.
+    /// Random listings get their default seed, other listings drop theirs
+    pub fn normalized(&self) -> ArtworkQueryOption {
+        let characters = self.characters.as_ref().and_then(|characters| {
+            let mut characters: Vec<String> = characters
+                .iter()
+                .map(|chara| chara.trim().to_owned())
+                .filter(|chara| !chara.is_empty())
+                .collect();
+            characters.sort();
+            characters.dedup();
+            if characters.is_empty() {
+                None
+            } else {
+                Some(characters)
+            }
+        });
+        ArtworkQueryOption {
+            characters,
+            image_type: self
+                .image_type
+                .as_ref()
+                .map(|image_type| image_type.to_uppercase()),
+            sort: Some(self.sort.unwrap_or(ArtworkSort::Newest)),
+            seed: match self.sort {
+                Some(ArtworkSort::Random) => {
+                    Some(self.seed.clone().unwrap_or_else(default_random_seed))
+                }
+                _ => None,
+            },
+            ..self.clone()
+        }
+    }
 }
 
 /// Condition applied to database queries
@@ -151,85 +388,628 @@ fn collection_name_by_artwork_type(artwork_type: &str) -> &str {
     }
 }
 
-/// Get artwork ids
-pub async fn get_ids(
-    db: &Database,
-    options: impl Into<Option<ArtworkQueryOption>>,
-) -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+/// Builds the collection name and `$match` stage of an id listing
+fn ids_filter(options: Option<&ArtworkQueryOption>) -> (String, Document) {
     let mut filtering = doc! { "$match": {} };
     let filtering_match = filtering.get_document_mut("$match").unwrap();
     let mut filtering_match_conditions = vec![];
     let mut collection_name = "artworks_sfw".to_owned();
-    if let Some(val) = options.into() {
-        filtering_match_conditions = filter_conditions(&val);
+    let mut upload_range = Document::new();
+    if let Some(val) = options {
+        filtering_match_conditions = filter_conditions(val);
         if let Some(artwork_type) = &val.image_type {
             collection_name = collection_name_by_artwork_type(artwork_type.as_str())
                 .parse()
                 .unwrap();
         }
+        if let Some(since) = val.since {
+            upload_range.insert("$gte", since);
+        }
+        if let Some(until) = val.until {
+            upload_range.insert("$lt", until);
+        }
+        let thresholds = [
+            ("like_count", val.min_likes),
+            ("love_count", val.min_loves),
+            ("view_count", val.min_views),
+        ];
+        for (field, threshold) in thresholds {
+            if let Some(threshold) = threshold.filter(|threshold| *threshold > 0) {
+                filtering_match.insert(field, doc! { "$gte": threshold });
+            }
+        }
     }
     if !filtering_match_conditions.is_empty() {
         filtering_match.insert("$or", filtering_match_conditions);
     }
+    if !upload_range.is_empty() {
+        filtering_match.insert("upload_timestamp", upload_range);
+    }
+    (collection_name, filtering)
+}
+
+/// Builds the collection name and aggregation pipeline of an id listing
+fn ids_query(options: Option<ArtworkQueryOption>) -> (String, Vec<Document>) {
+    let (collection_name, filtering) = ids_filter(options.as_ref());
+    let mut sort = ArtworkSort::Newest;
+    let mut seed = None;
+    let mut paging = vec![];
+    if let Some(val) = options {
+        sort = val.sort.unwrap_or(ArtworkSort::Newest);
+        seed = val.seed;
+        // Beyond `i64::MAX` both mean "everything" already, so they are clamped
+        if let Some(offset) = val.offset.filter(|offset| *offset > 0) {
+            paging.push(doc! { "$skip": i64::try_from(offset).unwrap_or(i64::MAX) });
+        }
+        if let Some(limit) = val.limit {
+            paging.push(doc! { "$limit": i64::try_from(limit).unwrap_or(i64::MAX) });
+        }
+    }
+    let mut pipeline = vec![filtering];
+    let seed = seed.unwrap_or_else(default_random_seed);
+    pipeline.extend(sort.stages(&seed));
+    pipeline.extend(paging);
+    pipeline.push(doc! { "$project": { "art_id": 1 } });
+    (collection_name, pipeline)
+}
+
+/// Get artwork ids
+pub async fn get_ids(
+    db: &Database,
+    options: impl Into<Option<ArtworkQueryOption>>,
+) -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_ids");
+    let options = options.into();
+    if options.as_ref().and_then(|val| val.limit) == Some(0) {
+        return Ok(vec![]);
+    }
+    let (collection_name, query_aggregate) = ids_query(options);
     let collection = db.collection::<ArtworkInfo>(&collection_name);
-    let query_aggregate = vec![
-        filtering,
-        doc! { "$sort": { "upload_timestamp": -1 } },
-        doc! { "$project": { "art_id": 1 } },
-    ];
     let cursor = collection.aggregate(query_aggregate, None).await?;
     let result = cursor
-        .map(|item| match item {
-            Ok(val) => {
-                if let Ok(art_id) = val.get_i32("art_id") {
-                    return art_id.into();
-                }
-                val.get_i64("art_id").unwrap_or(-1)
+        .filter_map(|item| match item {
+            Ok(document) => art_id_of(&document),
+            Err(e) => {
+                log::error!("get_ids cursor error {:?}", e);
+                None
+            }
+        })
+        .collect()
+        .await;
+    Ok(result)
+}
+
+/// Stream of artwork ids read from a cursor
+pub type IdStream = BoxStream<'static, Result<i64, mongodb::error::Error>>;
+
+/// Stream artwork ids straight from the cursor, so that memory stays flat
+/// regardless of the number of ids. Cursor errors end the stream
+pub async fn stream_ids(
+    db: &Database,
+    options: impl Into<Option<ArtworkQueryOption>>,
+) -> Result<IdStream, Box<dyn std::error::Error>> {
+    // Dropped with the stream, so that the time spent reading the cursor is included
+    let timer = metrics::db_timer("stream_ids");
+    let options = options.into();
+    if options.as_ref().and_then(|val| val.limit) == Some(0) {
+        return Ok(Box::pin(tokio_stream::empty()));
+    }
+    let (collection_name, query_aggregate) = ids_query(options);
+    let collection = db.collection::<ArtworkInfo>(&collection_name);
+    let cursor = collection.aggregate(query_aggregate, None).await?;
+    Ok(Box::pin(cursor.filter_map(move |item| {
+        let _timer = &timer;
+        match item {
+            Ok(document) => art_id_of(&document).map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    })))
+}
+
+/// Filters of a collection export. Unlike the listings, hidden artworks are included
+#[derive(Clone, Debug, Default)]
+pub struct ExportFilter {
+    /// `moderate.type`, e.g. `SFW`
+    pub rating: Option<String>,
+    /// `moderate.status`, e.g. `PASS`
+    pub status: Option<String>,
+    /// Earliest upload time, inclusive
+    pub since: Option<i64>,
+    /// Latest upload time, exclusive
+    pub until: Option<i64>,
+}
+
+impl ExportFilter {
+    fn query(&self) -> Document {
+        let mut query = doc! {};
+        if let Some(rating) = &self.rating {
+            query.insert("moderate.type", rating.to_uppercase());
+        }
+        if let Some(status) = &self.status {
+            query.insert("moderate.status", status.to_uppercase());
+        }
+        let mut upload_range = doc! {};
+        if let Some(since) = self.since {
+            upload_range.insert("$gte", since);
+        }
+        if let Some(until) = self.until {
+            upload_range.insert("$lt", until);
+        }
+        if !upload_range.is_empty() {
+            query.insert("upload_timestamp", upload_range);
+        }
+        query
+    }
+}
+
+/// Stream of artworks read from a cursor
+pub type ArtworkStream = BoxStream<'static, Result<ArtworkInfo, mongodb::error::Error>>;
+
+/// Stream every artwork matching the filter straight from the cursor, by ascending art id.
+/// Documents that don't fit `ArtworkInfo` are logged and skipped, see `validate_artworks`
+pub async fn stream_artworks(
+    db: &Database,
+    filter: &ExportFilter,
+) -> Result<ArtworkStream, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("stream_artworks");
+    let collection = db.collection::<Document>("artworks");
+    let cursor = collection
+        .find(
+            filter.query(),
+            FindOptions::builder()
+                .sort(doc! { "art_id": 1 })
+                .projection(doc! { "_id": 0, "change_seq": 0, "change_kind": 0, "changed_at": 0 })
+                .build(),
+        )
+        .await?;
+    Ok(Box::pin(cursor.filter_map(|item| match item {
+        Ok(document) => match parse_artwork(document) {
+            Ok(artwork) => Some(Ok(artwork)),
+            Err(invalid) => {
+                log::error!("Skip invalid artwork in export {:?}", invalid);
+                None
+            }
+        },
+        Err(e) => Some(Err(e)),
+    })))
+}
+
+/// Modulus of the seeded ordering, the largest prime below 2^31
+const SEEDED_ORDER_MODULUS: i64 = 2_147_483_647;
+
+/// FNV-1a hash of a seed, stable across platforms and releases
+pub(crate) fn seed_hash(seed: &str) -> u64 {
+    seed.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Pipeline stages ordering artworks by `(art_id * a + b) mod p`, with `a` and `b` derived from the seed.
+/// The order only depends on the seed and the matching ids, so it is reproducible
+fn seeded_order_stages(seed: &str) -> Vec<Document> {
+    let hash = seed_hash(seed);
+    let modulus = SEEDED_ORDER_MODULUS as u64;
+    let multiplier = (1 + hash % (modulus - 1)) as i64;
+    let increment = ((hash >> 32) % modulus) as i64;
+    vec![
+        doc! { "$addFields": { "_seeded": { "$mod": [
+            { "$add": [{ "$multiply": [{ "$toLong": "$art_id" }, multiplier] }, increment] },
+            SEEDED_ORDER_MODULUS,
+        ]}}},
+        doc! { "$sort": { "_seeded": 1, "art_id": 1 } },
+    ]
+}
+
+/// Get `count` random artwork ids matching the options.
+/// Without a seed `$sample` picks different ids every call,
+/// with a seed the same ids are picked for as long as the matching artworks don't change
+pub async fn get_random_ids(
+    db: &Database,
+    options: &ArtworkQueryOption,
+    count: u64,
+    seed: Option<&str>,
+) -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_random_ids");
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    let (collection_name, filtering) = ids_filter(Some(options));
+    let mut pipeline = vec![filtering];
+    match seed {
+        Some(seed) => {
+            pipeline.extend(seeded_order_stages(seed));
+            pipeline.push(doc! { "$limit": count as i64 });
+        }
+        None => pipeline.push(doc! { "$sample": { "size": count as i64 } }),
+    }
+    pipeline.push(doc! { "$project": { "art_id": 1 } });
+    let collection = db.collection::<ArtworkInfo>(&collection_name);
+    let cursor = collection.aggregate(pipeline, None).await?;
+    let result = cursor
+        .filter_map(|item| match item {
+            Ok(document) => art_id_of(&document),
+            Err(e) => {
+                log::error!("get_random_ids cursor error {:?}", e);
+                None
             }
-            Err(_) => -1,
         })
-        .filter(|val| *val != -1)
         .collect()
         .await;
     Ok(result)
 }
 
+/// Reads `art_id` from a document, which may be stored as either int32 or int64
+fn art_id_of(document: &Document) -> Option<i64> {
+    if let Ok(art_id) = document.get_i32("art_id") {
+        return Some(art_id.into());
+    }
+    if let Ok(art_id) = document.get_i64("art_id") {
+        return Some(art_id);
+    }
+    log::error!(
+        "Invalid art_id {:?} in document {:?}",
+        document.get("art_id"),
+        document.get("_id"),
+    );
+    None
+}
+
+/// Artwork document that exists in the database but does not fit `ArtworkInfo`
+#[derive(Clone, Debug, Serialize)]
+pub struct InvalidArtwork {
+    pub art_id: Option<i64>,
+    pub object_id: Option<String>,
+    pub error: String,
+}
+
+/// Result of looking up artworks by id.
+/// `artworks` preserves the order of the requested ids,
+/// `missing` lists ids without a document and `invalid` lists documents that failed to deserialize
+#[derive(Clone, Debug, Default)]
+pub struct ArtworkInfoLookup {
+    pub artworks: Vec<ArtworkInfo>,
+    pub missing: Vec<i64>,
+    pub invalid: Vec<InvalidArtwork>,
+}
+
+/// Deserializes an artwork document, describing the failure if it does not fit `ArtworkInfo`
+fn parse_artwork(document: Document) -> Result<ArtworkInfo, InvalidArtwork> {
+    let art_id = match document.get("art_id") {
+        Some(Bson::Int32(art_id)) => Some(i64::from(*art_id)),
+        Some(Bson::Int64(art_id)) => Some(*art_id),
+        _ => None,
+    };
+    let object_id = document.get_object_id("_id").ok().map(|oid| oid.to_hex());
+    bson::from_document::<ArtworkInfo>(document).map_err(|e| InvalidArtwork {
+        art_id,
+        object_id,
+        error: e.to_string(),
+    })
+}
+
 /// Get artwork info (metadata), e.g title, tags, url
 pub async fn get_artwork_info_by_ids(
     db: &Database,
     id_list: Vec<i64>,
-) -> Result<Vec<ArtworkInfo>, Box<dyn std::error::Error>> {
+) -> Result<ArtworkInfoLookup, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_info_by_ids");
+    lookup_artwork_info(db, "artworks", id_list).await
+}
+
+/// Get artwork info of the visible artworks of a rating (`SFW`, `NSFW` or `R18`).
+/// Artworks that are hidden or rated otherwise are reported missing
+pub async fn get_visible_artwork_info_by_ids(
+    db: &Database,
+    image_type: &str,
+    id_list: Vec<i64>,
+) -> Result<ArtworkInfoLookup, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_visible_artwork_info_by_ids");
+    lookup_artwork_info(db, collection_name_by_artwork_type(image_type), id_list).await
+}
+
+async fn lookup_artwork_info(
+    db: &Database,
+    collection_name: &str,
+    id_list: Vec<i64>,
+) -> Result<ArtworkInfoLookup, Box<dyn std::error::Error>> {
     if id_list.is_empty() {
-        return Ok(vec![]);
+        return Ok(ArtworkInfoLookup::default());
     }
-    let collection = db.collection::<ArtworkInfo>("artworks");
-    let filtering_match_conditions: Vec<Document> = id_list
-        .iter()
-        .map(|art_id| doc! { "art_id": art_id })
-        .collect();
-    let filtering = doc! { "$match": { "$or": filtering_match_conditions } };
+    let collection = db.collection::<ArtworkInfo>(collection_name);
+    let filtering = doc! { "$match": { "art_id": { "$in": &id_list } } };
     let pipeline = vec![filtering];
     let mut map: HashMap<i64, ArtworkInfo> = HashMap::with_capacity(id_list.len());
-    let mut result: Vec<ArtworkInfo> = vec![];
+    let mut lookup = ArtworkInfoLookup::default();
     let mut cursor = collection.aggregate(pipeline, None).await?;
     while let Some(cursor_result) = cursor.next().await {
-        if let Ok(document) = cursor_result {
-            if let Ok(artwork) = bson::from_document(document) {
-                let art: ArtworkInfo = artwork;
+        let document = match cursor_result {
+            Ok(document) => document,
+            Err(e) => {
+                log::error!("get_artwork_info_by_ids cursor error {:?}", e);
+                continue;
+            }
+        };
+        match parse_artwork(document) {
+            Ok(art) => {
                 map.insert(art.art_id, art);
             }
+            Err(invalid) => {
+                log::error!(
+                    "Deserialize artwork art_id={:?} {}",
+                    invalid.art_id,
+                    invalid.error
+                );
+                lookup.invalid.push(invalid);
+            }
         }
     }
     for art_id in id_list {
         if let Some(artwork) = map.get(&art_id) {
-            result.push(artwork.clone());
+            lookup.artworks.push(artwork.clone());
+        } else if !lookup
+            .invalid
+            .iter()
+            .any(|invalid| invalid.art_id == Some(art_id))
+        {
+            lookup.missing.push(art_id);
+        }
+    }
+    Ok(lookup)
+}
+
+/// Subset of `ArtworkInfo` fields to return, mapped to a `$project` stage
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ArtworkFields {
+    /// Grid view: `art_id`, `title`, `artist_id` and the `thumb_mini` urls
+    Thumb,
+    /// Card view: `thumb` plus characters, counters, upload time, rating and the `small` urls
+    Card,
+    /// The complete `ArtworkInfo`
+    Full,
+    /// Explicit list of (dotted) field paths
+    Custom(Vec<String>),
+}
+
+/// Field paths of `ArtworkInfo` that may be requested with `ArtworkFields::Custom`
+const PROJECTABLE_FIELDS: &[&str] = &[
+    "art_id",
+    "title",
+    "tag_str",
+    "characters",
+    "view_count",
+    "like_count",
+    "love_count",
+    "artist_id",
+    "upload_timestamp",
+    "is_404",
+    "sl",
+    "images",
+    "images.urls",
+    "images.urls.thumb_mini",
+    "images.urls.small",
+    "images.urls.regular",
+    "images.urls.original",
+    "images.nsfw",
+    "moderate",
+    "moderate.type",
+    "moderate.status",
+    "moderate.reason",
+];
+
+impl ArtworkFields {
+    /// Parses a preset name (`thumb`, `card`, `full`) or a comma separated list of field paths
+    pub fn parse(fields: &str) -> Result<Self, String> {
+        match fields.trim().to_lowercase().as_str() {
+            "" | "full" => Ok(ArtworkFields::Full),
+            "thumb" => Ok(ArtworkFields::Thumb),
+            "card" => Ok(ArtworkFields::Card),
+            _ => {
+                let paths: Vec<String> = fields
+                    .split(',')
+                    .map(|path| path.trim())
+                    .filter(|path| !path.is_empty())
+                    .map(|path| path.to_owned())
+                    .collect();
+                match paths
+                    .iter()
+                    .find(|path| !PROJECTABLE_FIELDS.contains(&path.as_str()))
+                {
+                    Some(path) => Err(format!("Unknown field {}", path)),
+                    None => Ok(ArtworkFields::Custom(paths)),
+                }
+            }
+        }
+    }
+
+    /// The `$project` specification, or `None` when every field is returned
+    pub fn projection(&self) -> Option<Document> {
+        let mut paths: Vec<&str> = match self {
+            ArtworkFields::Full => return None,
+            ArtworkFields::Thumb => vec!["title", "artist_id", "images.urls.thumb_mini"],
+            ArtworkFields::Card => vec![
+                "title",
+                "artist_id",
+                "characters",
+                "view_count",
+                "like_count",
+                "love_count",
+                "upload_timestamp",
+                "moderate.type",
+                "images.urls.thumb_mini",
+                "images.urls.small",
+            ],
+            ArtworkFields::Custom(paths) => paths.iter().map(|path| path.as_str()).collect(),
+        };
+        // parents first, so that a parent path supersedes its children (`images` vs `images.urls`)
+        paths.sort_by_key(|path| path.matches('.').count());
+        // art_id is always kept to order the result
+        let mut projection = doc! { "_id": 0, "art_id": 1 };
+        for path in paths {
+            if !paths_cover(&projection, path) {
+                projection.insert(path, 1);
+            }
+        }
+        Some(projection)
+    }
+}
+
+/// Checks whether a projection already includes `path` or one of its parents
+fn paths_cover(projection: &Document, path: &str) -> bool {
+    projection
+        .keys()
+        .any(|key| key != "_id" && (key == path || path.starts_with(&format!("{}.", key))))
+}
+
+/// Result of looking up projected artworks by id, see `ArtworkInfoLookup`
+#[derive(Clone, Debug, Default)]
+pub struct ArtworkFieldsLookup {
+    pub artworks: Vec<Document>,
+    pub missing: Vec<i64>,
+    pub invalid: Vec<InvalidArtwork>,
+}
+
+/// Applies an inclusion projection of `ArtworkFields::projection` to a document the way
+/// `$project` does: dotted paths reach into the documents of arrays, and paths missing from
+/// the document are left out
+fn project_document(document: &Document, projection: &Document) -> Document {
+    let mut projected = Document::new();
+    for (path, included) in projection {
+        if path == "_id" && included.as_i32() == Some(0) {
+            continue;
+        }
+        let path: Vec<&str> = path.split('.').collect();
+        if let Some(Bson::Document(value)) = project_path(&Bson::Document(document.clone()), &path)
+        {
+            merge_projection(&mut projected, value);
+        }
+    }
+    projected
+}
+
+/// The part of `value` under `path`, keeping the enclosing documents and arrays
+fn project_path(value: &Bson, path: &[&str]) -> Option<Bson> {
+    let (field, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Some(value.clone()),
+    };
+    match value {
+        Bson::Document(document) => {
+            let mut projected = Document::new();
+            if let Some(child) = document
+                .get(*field)
+                .and_then(|child| project_path(child, rest))
+            {
+                projected.insert(*field, child);
+            }
+            Some(Bson::Document(projected))
+        }
+        // Only the documents of an array have fields to project
+        Bson::Array(items) => Some(Bson::Array(
+            items
+                .iter()
+                .filter(|item| matches!(item, Bson::Document(_)))
+                .filter_map(|item| project_path(item, path))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Merges the projection of one path into the projection of the others
+fn merge_projection(projected: &mut Document, value: Document) {
+    for (field, value) in value {
+        match (projected.get_mut(&field), value) {
+            (Some(Bson::Document(existing)), Bson::Document(value)) => {
+                merge_projection(existing, value)
+            }
+            (Some(Bson::Array(existing)), Bson::Array(items)) => {
+                for (existing, item) in existing.iter_mut().zip(items) {
+                    if let (Bson::Document(existing), Bson::Document(item)) = (existing, item) {
+                        merge_projection(existing, item);
+                    }
+                }
+            }
+            (_, value) => {
+                projected.insert(field, value);
+            }
         }
     }
-    Ok(result)
+}
+
+/// Get a projection of artwork info by ids.
+/// Documents are validated against `ArtworkInfo` like `get_artwork_info_by_ids` does before
+/// they are projected, so that both report the same `invalid` documents
+pub async fn get_artwork_fields_by_ids(
+    db: &Database,
+    id_list: Vec<i64>,
+    projection: Document,
+) -> Result<ArtworkFieldsLookup, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_fields_by_ids");
+    if id_list.is_empty() {
+        return Ok(ArtworkFieldsLookup::default());
+    }
+    let collection = db.collection::<Document>("artworks");
+    let pipeline = vec![doc! { "$match": { "art_id": { "$in": &id_list } } }];
+    let mut map: HashMap<i64, Document> = HashMap::with_capacity(id_list.len());
+    let mut lookup = ArtworkFieldsLookup::default();
+    let mut cursor = collection.aggregate(pipeline, None).await?;
+    while let Some(cursor_result) = cursor.next().await {
+        let document = match cursor_result {
+            Ok(document) => document,
+            Err(e) => {
+                log::error!("get_artwork_fields_by_ids cursor error {:?}", e);
+                continue;
+            }
+        };
+        match parse_artwork(document.clone()) {
+            Ok(art) => {
+                map.insert(art.art_id, project_document(&document, &projection));
+            }
+            Err(invalid) => {
+                log::error!(
+                    "Deserialize artwork art_id={:?} {}",
+                    invalid.art_id,
+                    invalid.error
+                );
+                lookup.invalid.push(invalid);
+            }
+        }
+    }
+    for art_id in id_list {
+        if let Some(document) = map.remove(&art_id) {
+            lookup.artworks.push(document);
+        } else if !lookup
+            .invalid
+            .iter()
+            .any(|invalid| invalid.art_id == Some(art_id))
+        {
+            lookup.missing.push(art_id);
+        }
+    }
+    Ok(lookup)
+}
+
+/// Scan the whole artwork collection and report every document that does not fit `ArtworkInfo`
+pub async fn validate_artworks(
+    db: &Database,
+) -> Result<(u64, Vec<InvalidArtwork>), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("validate_artworks");
+    let collection = db.collection::<Document>("artworks");
+    let mut cursor = collection.find(None, None).await?;
+    let mut scanned = 0;
+    let mut invalid = vec![];
+    while let Some(document) = cursor.next().await {
+        scanned += 1;
+        if let Err(e) = parse_artwork(document?) {
+            invalid.push(e);
+        }
+    }
+    Ok((scanned, invalid))
 }
 
 /// Get upload time of the most recent upload
 pub async fn get_latest_upload_time(db: &Database) -> Result<i64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_latest_upload_time");
     let collection = db.collection::<ArtworkInfo>("artworks");
     let pipeline = vec![
         doc! { "$project": { "art_id": 1, "upload_timestamp": 1 } },
@@ -238,13 +1018,21 @@ pub async fn get_latest_upload_time(db: &Database) -> Result<i64, Box<dyn std::e
     ];
     let mut cursor = collection.aggregate(pipeline, None).await?;
     while let Some(val) = cursor.next().await {
-        if let Ok(document) = val {
-            if let Ok(upload_timestamp) = document.get_i32("upload_timestamp") {
-                return Ok(upload_timestamp.into());
-            }
-            if let Ok(upload_timestamp) = document.get_i64("upload_timestamp") {
-                return Ok(upload_timestamp);
+        match val {
+            Ok(document) => {
+                if let Ok(upload_timestamp) = document.get_i32("upload_timestamp") {
+                    return Ok(upload_timestamp.into());
+                }
+                if let Ok(upload_timestamp) = document.get_i64("upload_timestamp") {
+                    return Ok(upload_timestamp);
+                }
+                log::error!(
+                    "Invalid upload_timestamp {:?} art_id={:?}",
+                    document.get("upload_timestamp"),
+                    document.get("art_id"),
+                );
             }
+            Err(e) => log::error!("get_latest_upload_time cursor error {:?}", e),
         }
     }
     Ok(0)
@@ -252,6 +1040,7 @@ pub async fn get_latest_upload_time(db: &Database) -> Result<i64, Box<dyn std::e
 
 /// Get total artwork stored in the database
 pub async fn get_artwork_count_total(db: &Database) -> Result<u64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_count_total");
     let collection = db.collection::<ArtworkInfo>("artworks");
     let result = collection.count_documents(None, None).await?;
     Ok(result)
@@ -259,6 +1048,7 @@ pub async fn get_artwork_count_total(db: &Database) -> Result<u64, Box<dyn std::
 
 /// Get sfw artwork count
 pub async fn get_artwork_count_sfw(db: &Database) -> Result<u64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_count_sfw");
     let collection = db.collection::<ArtworkInfo>("artworks_sfw");
     let result = collection.count_documents(None, None).await?;
     Ok(result)
@@ -266,6 +1056,7 @@ pub async fn get_artwork_count_sfw(db: &Database) -> Result<u64, Box<dyn std::er
 
 /// Get nsfw artwork count
 pub async fn get_artwork_count_nsfw(db: &Database) -> Result<u64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_count_nsfw");
     let collection = db.collection::<ArtworkInfo>("artworks_nsfw");
     let result = collection.count_documents(None, None).await?;
     Ok(result)
@@ -273,39 +1064,1605 @@ pub async fn get_artwork_count_nsfw(db: &Database) -> Result<u64, Box<dyn std::e
 
 /// Get r18 artwork count
 pub async fn get_artwork_count_r18(db: &Database) -> Result<u64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_count_r18");
     let collection = db.collection::<ArtworkInfo>("artworks_r18");
     let result = collection.count_documents(None, None).await?;
     Ok(result)
 }
 
-/// Update database artwork
-/// TODO: waiting for mongodb rust driver to implement bulk write support
-pub async fn save_artwork_many(
-    db: &Database,
-    artwork_list: Vec<ArtworkInfo>,
-) -> Result<(), Box<dyn std::error::Error + '_>> {
-    let join_handles = artwork_list
-        .iter()
-        .map(|artwork| save_artwork_one(db, artwork.clone()));
-    let _result = join_all(join_handles).await;
-    Ok(())
+/// The rating views, see `create_views`
+const RATING_VIEWS: [&str; 3] = ["artworks_sfw", "artworks_nsfw", "artworks_r18"];
+
+/// Ratings (`moderate.type`) shown in the rating views
+const RATINGS: [&str; 3] = ["SFW", "NSFW", "R18"];
+
+/// Moderation statuses shown in the rating views
+const VISIBLE_STATUSES: [&str; 2] = ["PASS", "PUSH"];
+
+/// Aggregation over the visible artworks of every rating, to run on `RATING_VIEWS[0]`.
+/// `stages` run on each rating view and their results are combined
+fn across_rating_views(stages: Vec<Document>) -> Vec<Document> {
+    let mut pipeline = stages.clone();
+    for view in &RATING_VIEWS[1..] {
+        pipeline.push(doc! { "$unionWith": { "coll": *view, "pipeline": stages.clone() } });
+    }
+    pipeline
 }
 
-/// use `replace_one` to upsert an artwork entry
-pub async fn save_artwork_one(
+/// Number of visible artworks of a character per rating
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CharacterCount {
+    pub name: String,
+    pub sfw: u64,
+    pub nsfw: u64,
+    pub r18: u64,
+}
+
+/// Get the number of visible artworks per character and rating, most drawn characters first
+pub async fn get_character_counts(
     db: &Database,
-    artwork: ArtworkInfo,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<Vec<CharacterCount>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_character_counts");
+    let collection = db.collection::<ArtworkInfo>(RATING_VIEWS[0]);
+    let mut pipeline = across_rating_views(vec![
+        doc! { "$project": { "characters": 1, "moderate.type": 1 } },
+    ]);
+    pipeline.extend([
+        doc! { "$unwind": "$characters" },
+        doc! { "$group": {
+            "_id": { "name": "$characters", "type": "$moderate.type" },
+            "count": { "$sum": 1 },
+        }},
+    ]);
+    let cursor = collection.aggregate(pipeline, None).await?;
+    collect_character_counts(cursor).await
+}
+
+/// Folds `{ _id: { name, type }, count }` groups into per-character counts,
+/// most drawn characters first
+async fn collect_character_counts(
+    mut cursor: Cursor<Document>,
+) -> Result<Vec<CharacterCount>, Box<dyn std::error::Error>> {
+    let mut counts: HashMap<String, CharacterCount> = HashMap::new();
+    while let Some(document) = cursor.next().await {
+        let document = document?;
+        let group = document.get_document("_id")?;
+        let name = group.get_str("name")?;
+        let count = count_of(&document, "count");
+        let entry = counts
+            .entry(name.to_owned())
+            .or_insert_with(|| CharacterCount {
+                name: name.to_owned(),
+                ..Default::default()
+            });
+        match group.get_str("type").unwrap_or_default() {
+            "SFW" => entry.sfw += count,
+            "NSFW" => entry.nsfw += count,
+            "R18" => entry.r18 += count,
+            _ => {}
+        }
+    }
+    let mut result: Vec<CharacterCount> = counts.into_values().collect();
+    result.sort_by(|a, b| {
+        (b.sfw + b.nsfw + b.r18)
+            .cmp(&(a.sfw + a.nsfw + a.r18))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(result)
+}
+
+/// Get the characters drawn together with a character, with the number of visible artworks
+/// per rating they share. `patterns` are the regexes of the names the character is stored under
+pub async fn get_related_characters(
+    db: &Database,
+    patterns: &[String],
+    limit: usize,
+) -> Result<Vec<CharacterCount>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_related_characters");
+    let collection = db.collection::<ArtworkInfo>(RATING_VIEWS[0]);
+    let name_filters: Vec<Document> = patterns
+        .iter()
+        .map(|pattern| doc! { "characters": { "$regex": pattern, "$options": "i" } })
+        .collect();
+    if name_filters.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipeline = across_rating_views(vec![
+        doc! { "$match": { "$or": name_filters.clone() } },
+        doc! { "$project": { "characters": 1, "moderate.type": 1 } },
+    ]);
+    pipeline.extend([
+        doc! { "$unwind": "$characters" },
+        doc! { "$match": { "$nor": name_filters } },
+        doc! { "$group": {
+            "_id": { "name": "$characters", "type": "$moderate.type" },
+            "count": { "$sum": 1 },
+        }},
+    ]);
+    let cursor = collection.aggregate(pipeline, None).await?;
+    let mut result = collect_character_counts(cursor).await?;
+    result.truncate(limit);
+    Ok(result)
+}
+
+/// Most recent visible artwork id of a character per rating, to use as cover image
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CharacterCovers {
+    pub sfw: Option<i64>,
+    pub nsfw: Option<i64>,
+    pub r18: Option<i64>,
+}
+
+/// Get the most recent visible artwork id of every character per rating
+pub async fn get_character_covers(
+    db: &Database,
+) -> Result<HashMap<String, CharacterCovers>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_character_covers");
+    let collection = db.collection::<ArtworkInfo>(RATING_VIEWS[0]);
+    let pipeline = across_rating_views(vec![
+        doc! { "$sort": { "upload_timestamp": -1, "art_id": -1 } },
+        doc! { "$project": { "art_id": 1, "characters": 1, "moderate.type": 1 } },
+        doc! { "$unwind": "$characters" },
+        doc! { "$group": {
+            "_id": { "name": "$characters", "type": "$moderate.type" },
+            "art_id": { "$first": "$art_id" },
+        }},
+    ]);
+    let mut covers: HashMap<String, CharacterCovers> = HashMap::new();
+    let mut cursor = collection.aggregate(pipeline, None).await?;
+    while let Some(document) = cursor.next().await {
+        let document = document?;
+        let group = document.get_document("_id")?;
+        let art_id = match art_id_of(&document) {
+            Some(art_id) => art_id,
+            None => continue,
+        };
+        let entry = covers.entry(group.get_str("name")?.to_owned()).or_default();
+        match group.get_str("type").unwrap_or_default() {
+            "SFW" => entry.sfw = Some(art_id),
+            "NSFW" => entry.nsfw = Some(art_id),
+            "R18" => entry.r18 = Some(art_id),
+            _ => {}
+        }
+    }
+    Ok(covers)
+}
+
+/// Reads a count produced by `$sum`, which may be stored as either int32 or int64
+fn count_of(document: &Document, key: &str) -> u64 {
+    match document.get(key) {
+        Some(Bson::Int32(count)) => (*count).max(0) as u64,
+        Some(Bson::Int64(count)) => (*count).max(0) as u64,
+        _ => 0,
+    }
+}
+
+/// Number of visible artworks of an artist
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ArtistCount {
+    pub artist_id: i64,
+    pub count: u64,
+}
+
+/// Get the artists with the most visible artworks
+pub async fn get_top_artists(
+    db: &Database,
+    limit: u64,
+) -> Result<Vec<ArtistCount>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_top_artists");
+    let collection = db.collection::<ArtworkInfo>(RATING_VIEWS[0]);
+    let mut pipeline = across_rating_views(vec![doc! { "$project": { "artist_id": 1 } }]);
+    pipeline.extend([
+        doc! { "$group": { "_id": "$artist_id", "count": { "$sum": 1 } } },
+        doc! { "$sort": { "count": -1, "_id": 1 } },
+        doc! { "$limit": i64::try_from(limit).unwrap_or(i64::MAX) },
+    ]);
+    let mut result = vec![];
+    let mut cursor = collection.aggregate(pipeline, None).await?;
+    while let Some(document) = cursor.next().await {
+        let document = document?;
+        let artist_id = match document.get("_id") {
+            Some(Bson::Int32(artist_id)) => i64::from(*artist_id),
+            Some(Bson::Int64(artist_id)) => *artist_id,
+            _ => continue,
+        };
+        result.push(ArtistCount {
+            artist_id,
+            count: count_of(&document, "count"),
+        });
+    }
+    Ok(result)
+}
+
+/// Number of visible artworks uploaded within a day (`2022-03-08`) or ISO week (`2022-W10`)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UploadBucket {
+    pub period: String,
+    pub count: u64,
+}
+
+/// Get the number of visible uploads per period since a unix timestamp, oldest first.
+/// `period_format` is a `$dateToString` format, e.g. `%Y-%m-%d` for days or `%G-W%V` for weeks
+pub async fn get_upload_histogram(
+    db: &Database,
+    since: i64,
+    period_format: &str,
+) -> Result<Vec<UploadBucket>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_upload_histogram");
+    let collection = db.collection::<ArtworkInfo>(RATING_VIEWS[0]);
+    let mut pipeline = across_rating_views(vec![
+        doc! { "$match": { "upload_timestamp": { "$gte": since } } },
+        doc! { "$project": { "upload_timestamp": 1 } },
+    ]);
+    pipeline.extend([
+        doc! { "$group": {
+            "_id": { "$dateToString": {
+                "format": period_format,
+                "date": { "$toDate": { "$multiply": [{ "$toLong": "$upload_timestamp" }, 1000] } },
+            }},
+            "count": { "$sum": 1 },
+        }},
+        doc! { "$sort": { "_id": 1 } },
+    ]);
+    let mut result = vec![];
+    let mut cursor = collection.aggregate(pipeline, None).await?;
+    while let Some(document) = cursor.next().await {
+        let document = document?;
+        result.push(UploadBucket {
+            period: document.get_str("_id")?.to_owned(),
+            count: count_of(&document, "count"),
+        });
+    }
+    Ok(result)
+}
+
+/// Matches the artworks waiting for moderation: still on the source platform, but without
+/// a rating and status that would show them in the rating views
+fn pending_filter() -> Document {
+    doc! {
+        "is_404": { "$ne": true },
+        "$or": [
+            { "moderate.type": { "$nin": RATINGS.to_vec() } },
+            { "moderate.status": { "$nin": VISIBLE_STATUSES.to_vec() } },
+        ],
+    }
+}
+
+/// Get the number of artworks waiting for moderation
+pub async fn get_artwork_count_pending(db: &Database) -> Result<u64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_count_pending");
     let collection = db.collection::<ArtworkInfo>("artworks");
+    let result = collection.count_documents(pending_filter(), None).await?;
+    Ok(result)
+}
+
+/// Get the number of artworks removed from the source platform
+pub async fn get_artwork_count_404(db: &Database) -> Result<u64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_artwork_count_404");
+    let collection = db.collection::<ArtworkInfo>("artworks");
+    let result = collection
+        .count_documents(doc! { "is_404": true }, None)
+        .await?;
+    Ok(result)
+}
+
+/// Update database artwork
+/// TODO: waiting for mongodb rust driver to implement bulk write support
+pub async fn save_artwork_many(
+    db: &Database,
+    artwork_list: Vec<ArtworkInfo>,
+) -> Result<Vec<SavedArtwork>, Box<dyn std::error::Error + '_>> {
+    let _timer = metrics::db_timer("save_artwork_many");
+    let saved = save_artwork_batch(db, artwork_list)
+        .await
+        .into_iter()
+        .filter_map(Result::ok)
+        .collect();
+    Ok(saved)
+}
+
+/// Update database artworks concurrently, returning the outcome of each artwork in order.
+/// The write version is bumped once for the batch
+pub async fn save_artwork_batch(
+    db: &Database,
+    artwork_list: Vec<ArtworkInfo>,
+) -> Vec<Result<SavedArtwork, Box<dyn std::error::Error>>> {
+    let _timer = metrics::db_timer("save_artwork_batch");
+    if artwork_list.is_empty() {
+        return vec![];
+    }
+    let first_change = match reserve_change_seqs(db, artwork_list.len() as i64).await {
+        Ok(first_change) => first_change,
+        Err(e) => {
+            log::error!("Reserve change numbers {:?}", e);
+            let message = e.to_string();
+            return artwork_list
+                .iter()
+                .map(|artwork| {
+                    metrics::record_sync_upsert(false);
+                    Err(format!("failed to save artwork {}: {}", artwork.art_id, message).into())
+                })
+                .collect();
+        }
+    };
+    let join_handles = artwork_list
+        .into_iter()
+        .zip(first_change..)
+        .map(|(artwork, change)| save_artwork_one(db, artwork, change));
+    let results = join_all(join_handles).await;
+    // Numbers of failed writes stay unused, the feed doesn't mind gaps
+    if let Err(e) = release_change_seqs(db, first_change).await {
+        log::warn!("Release change numbers {:?}", e);
+    }
+    for result in &results {
+        metrics::record_sync_upsert(result.is_ok());
+    }
+    if let Err(e) = bump_write_version(db).await {
+        log::error!("Bump write version {:?}", e);
+    }
+    results
+}
+
+/// Whether an artwork with these fields shows up in the rating views
+fn is_visible(is_404: Option<bool>, art_type: Option<&str>, status: Option<&str>) -> bool {
+    is_404 != Some(true)
+        && art_type.is_some_and(|art_type| RATINGS.contains(&art_type))
+        && status.is_some_and(|status| VISIBLE_STATUSES.contains(&status))
+}
+
+/// Whether an artwork document shows up in the rating views
+fn is_document_visible(document: &Document) -> bool {
+    let moderate = document.get_document("moderate").ok();
+    is_visible(
+        document.get_bool("is_404").ok(),
+        moderate.and_then(|moderate| moderate.get_str("type").ok()),
+        moderate.and_then(|moderate| moderate.get_str("status").ok()),
+    )
+}
+
+/// An artwork written by db sync, with its visibility before the write
+#[derive(Clone, Debug)]
+pub struct SavedArtwork {
+    pub artwork: ArtworkInfo,
+    /// Whether a document of the artwork existed before
+    pub existed: bool,
+    pub was_visible: bool,
+    /// `moderate.type` of the document before the write
+    pub previous_rating: Option<String>,
+}
+
+impl SavedArtwork {
+    pub fn is_visible(&self) -> bool {
+        let moderate = self.artwork.moderate.as_ref();
+        is_visible(
+            self.artwork.is_404,
+            moderate.and_then(|moderate| moderate.art_type.as_deref()),
+            moderate.and_then(|moderate| moderate.status.as_deref()),
+        )
+    }
+
+    /// Whether the write made the artwork show up in the rating views
+    pub fn became_visible(&self) -> bool {
+        !self.was_visible && self.is_visible()
+    }
+
+    /// `moderate.type` of the artwork as written
+    pub fn rating(&self) -> Option<&str> {
+        self.artwork.moderate.as_ref()?.art_type.as_deref()
+    }
+}
+
+/// Open a change stream over the artwork writes stamped `created`, which made an artwork
+/// visible, see `artwork_write_pipeline`. Events carry the artwork as looked up after the
+/// write in `fullDocument`, and are resumed after the event `resume_after` if given.
+/// The stream can't tell a sync from `backfill_change_seqs`, see `is_upload_change`
+pub async fn watch_uploads(
+    db: &Database,
+    resume_after: Option<Document>,
+) -> Result<Cursor<Document>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("watch_uploads");
+    let mut change_stream = doc! { "fullDocument": "updateLookup" };
+    if let Some(resume_after) = resume_after {
+        change_stream.insert("resumeAfter", resume_after);
+    }
+    let pipeline = vec![
+        doc! { "$changeStream": change_stream },
+        doc! {
+            "$match": {
+                "operationType": { "$in": ["insert", "replace", "update"] },
+                "fullDocument.change_kind": bson::to_bson(&ChangeKind::Created)?,
+            }
+        },
+    ];
+    let options = AggregateOptions::builder()
+        .max_await_time(CHANGE_STREAM_AWAIT)
+        .build();
+    let collection = db.collection::<Document>("artworks");
+    Ok(collection.aggregate(pipeline, options).await?)
+}
+
+/// Whether an error of `watch_uploads` means the deployment doesn't support change streams
+pub fn change_streams_unsupported(error: &(dyn std::error::Error + 'static)) -> bool {
+    command_error_code(error).is_some_and(|code| CHANGE_STREAMS_UNSUPPORTED.contains(&code))
+}
+
+/// The code of an error the server answered a command with. `None` for other errors,
+/// e.g. when the server couldn't be reached
+pub fn command_error_code(error: &(dyn std::error::Error + 'static)) -> Option<i32> {
+    match error.downcast_ref::<mongodb::error::Error>()?.kind.as_ref() {
+        ErrorKind::Command(error) => Some(error.code),
+        _ => None,
+    }
+}
+
+/// Whether an event of `watch_uploads` is a write that made a still visible artwork visible.
+/// Updates must have stamped `created` themselves and written more than the change fields,
+/// which leaves out `backfill_change_seqs`
+pub fn is_upload_change(change: &Document) -> bool {
+    let artwork = match change.get_document("fullDocument") {
+        Ok(artwork) => artwork,
+        Err(_) => return false,
+    };
+    let created = bson::to_bson(&ChangeKind::Created).ok();
+    let written = match change.get_str("operationType") {
+        Ok("insert") | Ok("replace") => true,
+        Ok("update") => change
+            .get_document("updateDescription")
+            .and_then(|description| description.get_document("updatedFields"))
+            .map(|updated| {
+                updated.get("change_kind") == created.as_ref()
+                    && updated.keys().any(|field| {
+                        let field = field.split('.').next().unwrap_or_default();
+                        !CHANGE_FIELDS.contains(&field)
+                    })
+            })
+            .unwrap_or(false),
+        _ => false,
+    };
+    written && artwork.get("change_kind") == created.as_ref() && is_document_visible(artwork)
+}
+
+/// Version of the artwork data, bumped on every db sync.
+/// It is stored in the database so that every replica agrees on it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WriteVersion {
+    pub generation: i64,
+    /// Unix timestamp (seconds) of the last write
+    pub updated_at: i64,
+}
+
+/// Get the current write version. Before the first sync, the latest upload time stands in
+/// for the last write
+pub async fn get_write_version(db: &Database) -> Result<WriteVersion, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_write_version");
+    let collection = db.collection::<Document>(META_COLLECTION);
     match collection
-        .replace_one(
-            doc! { "art_id": artwork.art_id },
-            artwork.clone(),
-            ReplaceOptions::builder().upsert(true).build(),
+        .find_one(doc! { "_id": WRITE_VERSION_ID }, None)
+        .await?
+    {
+        Some(document) => Ok(WriteVersion {
+            generation: document.get_i64("generation").unwrap_or_default(),
+            updated_at: document.get_i64("updated_at").unwrap_or_default(),
+        }),
+        None => Ok(WriteVersion {
+            generation: 0,
+            updated_at: get_latest_upload_time(db).await?,
+        }),
+    }
+}
+
+/// Increment the write version after artworks were written
+pub async fn bump_write_version(db: &Database) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("bump_write_version");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let collection = db.collection::<Document>(META_COLLECTION);
+    collection
+        .update_one(
+            doc! { "_id": WRITE_VERSION_ID },
+            doc! {
+                "$inc": { "generation": 1_i64 },
+                "$set": { "updated_at": now },
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Record the engagement counters of an artwork in the snapshot of the current interval.
+/// Snapshots are what `get_trending` measures growth with, since syncs overwrite the counters
+pub async fn record_engagement_snapshot(
+    db: &Database,
+    artwork: &ArtworkInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("record_engagement_snapshot");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let collection = db.collection::<Document>(SNAPSHOT_COLLECTION);
+    collection
+        .update_one(
+            doc! { "art_id": artwork.art_id, "bucket": now / SNAPSHOT_INTERVAL_SECS },
+            doc! { "$set": {
+                "taken_at": DateTime::now(),
+                "upload_timestamp": artwork.upload_timestamp,
+                "view_count": artwork.view_count,
+                "like_count": artwork.like_count,
+                "love_count": artwork.love_count,
+            }},
+            UpdateOptions::builder().upsert(true).build(),
         )
+        .await?;
+    Ok(())
+}
+
+/// Engagement gained by an artwork over a trending window
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TrendingArtwork {
+    pub art_id: i64,
+    pub score: f64,
+    pub view_gain: i64,
+    pub like_gain: i64,
+    pub love_gain: i64,
+}
+
+/// Get the artworks of a rating view that gained the most engagement within the last `window_secs`.
+/// The score weighs a love twice as much as a like, and a hundred views as much as a like.
+/// Artworks uploaded within the window count their gains from zero
+pub async fn get_trending(
+    db: &Database,
+    image_type: &str,
+    window_secs: i64,
+    limit: u64,
+) -> Result<Vec<TrendingArtwork>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_trending");
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let since = now - window_secs.clamp(0, TRENDING_MAX_WINDOW_SECS);
+    let gain = |field: &str| {
+        doc! { "$subtract": [
+            format!("$last_{}", field),
+            { "$cond": [{ "$gte": ["$upload_timestamp", since] }, 0, format!("$first_{}", field)] },
+        ]}
+    };
+    let pipeline = vec![
+        doc! { "$match": { "taken_at": { "$gte": DateTime::from_millis(since * 1000) } } },
+        doc! { "$sort": { "taken_at": 1 } },
+        doc! { "$group": {
+            "_id": "$art_id",
+            "upload_timestamp": { "$last": "$upload_timestamp" },
+            "first_view_count": { "$first": "$view_count" },
+            "first_like_count": { "$first": "$like_count" },
+            "first_love_count": { "$first": "$love_count" },
+            "last_view_count": { "$last": "$view_count" },
+            "last_like_count": { "$last": "$like_count" },
+            "last_love_count": { "$last": "$love_count" },
+        }},
+        doc! { "$project": {
+            "_id": 0,
+            "art_id": "$_id",
+            "view_gain": gain("view_count"),
+            "like_gain": gain("like_count"),
+            "love_gain": gain("love_count"),
+        }},
+        doc! { "$addFields": {
+            "score": { "$add": [
+                "$like_gain",
+                { "$multiply": ["$love_gain", 2] },
+                { "$divide": ["$view_gain", 100] },
+            ]},
+        }},
+        doc! { "$match": { "score": { "$gt": 0 } } },
+        doc! { "$sort": { "score": -1, "art_id": -1 } },
+        doc! { "$lookup": {
+            "from": collection_name_by_artwork_type(image_type),
+            "localField": "art_id",
+            "foreignField": "art_id",
+            "as": "visible",
+        }},
+        doc! { "$match": { "visible.0": { "$exists": true } } },
+        doc! { "$limit": limit as i64 },
+        doc! { "$project": { "visible": 0 } },
+    ];
+    let collection = db.collection::<Document>(SNAPSHOT_COLLECTION);
+    let mut cursor = collection.aggregate(pipeline, None).await?;
+    let mut result = vec![];
+    while let Some(document) = cursor.next().await {
+        match bson::from_document::<TrendingArtwork>(document?) {
+            Ok(trending) => result.push(trending),
+            Err(e) => log::error!("Deserialize trending artwork {}", e),
+        }
+    }
+    Ok(result)
+}
+
+/// An artwork featured on a date (`YYYY-MM-DD`, UTC).
+/// `source` is `scheduled` for slots set by an admin and `auto` for fallback picks
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FeaturedSlot {
+    pub date: String,
+    pub art_id: i64,
+    pub source: String,
+}
+
+/// Get the featured slots between two dates, inclusive
+pub async fn get_featured_slots(
+    db: &Database,
+    from: &str,
+    to: &str,
+) -> Result<Vec<FeaturedSlot>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_featured_slots");
+    let collection = db.collection::<FeaturedSlot>(FEATURED_COLLECTION);
+    let cursor = collection
+        .find(doc! { "date": { "$gte": from, "$lte": to } }, None)
+        .await?;
+    let result = cursor
+        .filter_map(|item| match item {
+            Ok(slot) => Some(slot),
+            Err(e) => {
+                log::error!("get_featured_slots cursor error {:?}", e);
+                None
+            }
+        })
+        .collect()
+        .await;
+    Ok(result)
+}
+
+/// Save a featured slot. An existing slot of the date is replaced only if `replace` is set,
+/// so that concurrent fallback picks don't override each other or an admin's schedule
+pub async fn save_featured_slot(
+    db: &Database,
+    slot: &FeaturedSlot,
+    replace: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("save_featured_slot");
+    let collection = db.collection::<FeaturedSlot>(FEATURED_COLLECTION);
+    let fields = doc! { "art_id": slot.art_id, "source": &slot.source };
+    let update = if replace {
+        doc! { "$set": fields }
+    } else {
+        doc! { "$setOnInsert": fields }
+    };
+    collection
+        .update_one(
+            doc! { "date": &slot.date },
+            update,
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Remove the featured slot of a date. Returns whether a slot existed
+pub async fn delete_featured_slot(
+    db: &Database,
+    date: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("delete_featured_slot");
+    let collection = db.collection::<FeaturedSlot>(FEATURED_COLLECTION);
+    let result = collection.delete_one(doc! { "date": date }, None).await?;
+    Ok(result.deleted_count > 0)
+}
+
+/// Check whether an artwork is visible in the view of an artwork type
+pub async fn is_artwork_visible(
+    db: &Database,
+    art_id: i64,
+    image_type: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("is_artwork_visible");
+    let collection = db.collection::<ArtworkInfo>(collection_name_by_artwork_type(image_type));
+    let count = collection
+        .count_documents(doc! { "art_id": art_id }, None)
+        .await?;
+    Ok(count > 0)
+}
+
+/// Upsert an artwork entry, stamping it with change number `change` in the same write,
+/// see `artwork_write_pipeline`
+pub async fn save_artwork_one(
+    db: &Database,
+    artwork: ArtworkInfo,
+    change: i64,
+) -> Result<SavedArtwork, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("save_artwork_one");
+    // The previous document is read as a plain document since it may not fit `ArtworkInfo`
+    let collection = db.collection::<Document>("artworks");
+    let moderate = artwork.moderate.as_ref();
+    let visible = is_visible(
+        artwork.is_404,
+        moderate.and_then(|moderate| moderate.art_type.as_deref()),
+        moderate.and_then(|moderate| moderate.status.as_deref()),
+    );
+    let pipeline = artwork_write_pipeline(bson::to_document(&artwork)?, visible, change)?;
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::Before)
+        .projection(doc! { "is_404": 1, "moderate": 1 })
+        .build();
+    match collection
+        .find_one_and_update(doc! { "art_id": artwork.art_id }, pipeline, options)
         .await
     {
-        Ok(_) => Ok(()),
-        _ => Err(format!("failed to save artwork {:?}", artwork).into()),
+        Ok(before) => {
+            let previous_rating = before
+                .as_ref()
+                .and_then(|before| before.get_document("moderate").ok())
+                .and_then(|moderate| moderate.get_str("type").ok())
+                .map(str::to_owned);
+            let saved = SavedArtwork {
+                existed: before.is_some(),
+                was_visible: before.as_ref().map(is_document_visible).unwrap_or(false),
+                previous_rating,
+                artwork,
+            };
+            if let Err(e) = record_engagement_snapshot(db, &saved.artwork).await {
+                log::warn!(
+                    "Engagement snapshot art_id={} {:?}",
+                    saved.artwork.art_id,
+                    e
+                );
+            }
+            Ok(saved)
+        }
+        Err(e) => {
+            log::error!("Save artwork art_id={} {:?}", artwork.art_id, e);
+            Err(format!("failed to save artwork {}: {}", artwork.art_id, e).into())
+        }
+    }
+}
+
+/// Whether the stored document shows up in the rating views, as an aggregation expression.
+/// The counterpart of `is_visible`
+fn visible_expr() -> Document {
+    doc! {
+        "$and": [
+            { "$ne": ["$is_404", true] },
+            { "$in": ["$moderate.type", RATINGS.to_vec()] },
+            { "$in": ["$moderate.status", VISIBLE_STATUSES.to_vec()] },
+        ]
+    }
+}
+
+/// Update pipeline replacing an artwork document and stamping its change fields in one write.
+///
+/// Visible artworks get change number `change` as `created` or `updated`, depending on whether
+/// the stored document was visible. Hidden artworks get it as a `hidden` tombstone if they were
+/// visible, and keep their previous stamp (or none) otherwise, so that rewriting a hidden
+/// artwork doesn't erase its takedown from the feed. `changed_at` is the server's clock
+fn artwork_write_pipeline(
+    replacement: Document,
+    visible: bool,
+    change: i64,
+) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+    let stamp = |kind: Bson| {
+        doc! {
+            "change_seq": change,
+            "change_kind": kind,
+            "changed_at": "$$NOW",
+        }
+    };
+    let change_fields = if visible {
+        stamp(Bson::Document(doc! {
+            "$cond": [
+                visible_expr(),
+                bson::to_bson(&ChangeKind::Updated)?,
+                bson::to_bson(&ChangeKind::Created)?,
+            ]
+        }))
+    } else {
+        // Fields of a missing previous stamp stay missing
+        doc! {
+            "$cond": [
+                visible_expr(),
+                stamp(bson::to_bson(&ChangeKind::Hidden)?),
+                {
+                    "change_seq": "$change_seq",
+                    "change_kind": "$change_kind",
+                    "changed_at": "$changed_at",
+                },
+            ]
+        }
+    };
+    Ok(vec![doc! {
+        "$replaceWith": {
+            "$mergeObjects": [
+                { "_id": "$_id" },
+                // Values such as a title starting with `$` are not expressions
+                { "$literal": replacement },
+                change_fields,
+            ]
+        }
+    }])
+}
+
+/// How an artwork changed for the changes feed
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// The artwork became visible
+    Created,
+    /// A visible artwork was written again
+    Updated,
+    /// The artwork stopped being visible, mirrors should drop it
+    Hidden,
+}
+
+/// An entry of the changes feed: the latest change of an artwork.
+/// `artwork` is left out for hidden artworks
+#[derive(Clone, Debug, Serialize)]
+pub struct ArtworkChange {
+    pub change: i64,
+    pub kind: ChangeKind,
+    pub art_id: i64,
+    /// Unix timestamp (seconds) of the change
+    pub changed_at: i64,
+    pub artwork: Option<ArtworkInfo>,
+}
+
+/// The pending reservations of the change sequence counter that are not abandoned,
+/// as an aggregation expression
+fn live_change_reservations() -> Document {
+    doc! {
+        "$filter": {
+            "input": { "$ifNull": ["$pending", []] },
+            "cond": {
+                "$gt": ["$$this.at", { "$subtract": ["$$NOW", CHANGE_RESERVATION_EXPIRY_MS] }]
+            },
+        }
+    }
+}
+
+/// Reserve `count` consecutive change numbers and return the first one.
+/// The reservation is recorded with the numbers in one write, and until it is released the
+/// changes feed holds back every change from its first number on, so that a reader never
+/// moves past a number whose write is still in flight
+async fn reserve_change_seqs(db: &Database, count: i64) -> Result<i64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("reserve_change_seqs");
+    let collection = db.collection::<Document>(META_COLLECTION);
+    let value = doc! { "$ifNull": ["$value", 0_i64] };
+    let counter = collection
+        .find_one_and_update(
+            doc! { "_id": CHANGE_SEQUENCE_ID },
+            vec![doc! {
+                "$set": {
+                    "value": { "$add": [value.clone(), count] },
+                    // Abandoned reservations are dropped along the way
+                    "pending": {
+                        "$concatArrays": [
+                            live_change_reservations(),
+                            [{ "first": { "$add": [value, 1_i64] }, "at": "$$NOW" }],
+                        ]
+                    },
+                }
+            }],
+            FindOneAndUpdateOptions::builder()
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .ok_or("change sequence counter missing after upsert")?;
+    Ok(counter.get_i64("value")? - count + 1)
+}
+
+/// Release the reservation starting at change number `first`
+async fn release_change_seqs(db: &Database, first: i64) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("release_change_seqs");
+    let collection = db.collection::<Document>(META_COLLECTION);
+    collection
+        .update_one(
+            doc! { "_id": CHANGE_SEQUENCE_ID },
+            doc! { "$pull": { "pending": { "first": first } } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// The lowest change number of a write still in flight, if any
+async fn lowest_pending_change(db: &Database) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("lowest_pending_change");
+    let collection = db.collection::<Document>(META_COLLECTION);
+    let mut cursor = collection
+        .aggregate(
+            vec![
+                doc! { "$match": { "_id": CHANGE_SEQUENCE_ID } },
+                doc! {
+                    "$project": {
+                        "lowest": {
+                            "$min": {
+                                "$map": { "input": live_change_reservations(), "in": "$$this.first" }
+                            }
+                        }
+                    }
+                },
+            ],
+            None,
+        )
+        .await?;
+    match cursor.next().await {
+        Some(document) => Ok(document?.get_i64("lowest").ok()),
+        None => Ok(None),
+    }
+}
+
+/// Stamp the visible artworks written before the changes feed existed as `created`,
+/// so that reading the feed from 0 lists every visible artwork.
+/// Artworks stamped by a sync in the meantime are left alone. Returns the number stamped
+pub async fn backfill_change_seqs(db: &Database) -> Result<u64, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("backfill_change_seqs");
+    let collection = db.collection::<Document>("artworks");
+    let unstamped = doc! { "change_seq": { "$exists": false }, "$expr": visible_expr() };
+    let mut stamped = 0;
+    loop {
+        let ids: Vec<Bson> = collection
+            .find(
+                unstamped.clone(),
+                FindOptions::builder()
+                    .projection(doc! { "_id": 1 })
+                    .limit(CHANGE_BACKFILL_CHUNK)
+                    .build(),
+            )
+            .await?
+            .filter_map(|document| document.ok()?.get("_id").cloned())
+            .collect()
+            .await;
+        if ids.is_empty() {
+            return Ok(stamped);
+        }
+        let first = reserve_change_seqs(db, ids.len() as i64).await?;
+        let kind = bson::to_bson(&ChangeKind::Created)?;
+        let updates = ids.into_iter().zip(first..).map(|(id, change)| {
+            collection.update_one(
+                doc! { "_id": id, "change_seq": { "$exists": false } },
+                vec![doc! {
+                    "$set": {
+                        "change_seq": change,
+                        "change_kind": kind.clone(),
+                        "changed_at": "$$NOW",
+                    }
+                }],
+                None,
+            )
+        });
+        let results = join_all(updates).await;
+        if let Err(e) = release_change_seqs(db, first).await {
+            log::warn!("Release change numbers {:?}", e);
+        }
+        for result in results {
+            stamped += result?.modified_count;
+        }
+    }
+}
+
+/// A page of the changes feed
+#[derive(Clone, Debug, Serialize)]
+pub struct ChangesPage {
+    pub changes: Vec<ArtworkChange>,
+    /// The token to read the next page from
+    pub next: i64,
+    pub has_more: bool,
+}
+
+/// Get the latest changes after the change number `since`, oldest first.
+/// Changes from the lowest number of a write still in flight on are held back, so `next`
+/// never moves past a number that is yet to appear. Documents that don't fit `ArtworkInfo`
+/// are logged and skipped, see `validate_artworks`
+pub async fn get_changes(
+    db: &Database,
+    since: i64,
+    limit: i64,
+) -> Result<ChangesPage, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_changes");
+    let mut range = doc! { "$gt": since };
+    if let Some(lowest) = lowest_pending_change(db).await? {
+        range.insert("$lt", lowest);
+    }
+    let collection = db.collection::<Document>("artworks");
+    let mut cursor = collection
+        .find(
+            doc! { "change_seq": range },
+            FindOptions::builder()
+                .sort(doc! { "change_seq": 1 })
+                .limit(limit)
+                .build(),
+        )
+        .await?;
+    let mut page = ChangesPage {
+        changes: vec![],
+        next: since,
+        has_more: false,
+    };
+    let mut scanned = 0;
+    while let Some(document) = cursor.next().await {
+        let document = document?;
+        let change = document.get_i64("change_seq")?;
+        scanned += 1;
+        page.next = change;
+        match parse_change(change, document) {
+            Ok(change) => page.changes.push(change),
+            Err(e) => log::error!("Skip invalid change {} {}", change, e),
+        }
+    }
+    page.has_more = scanned == limit;
+    Ok(page)
+}
+
+/// Reads the feed entry of a stamped artwork document
+fn parse_change(change: i64, document: Document) -> Result<ArtworkChange, String> {
+    let kind: ChangeKind = document
+        .get("change_kind")
+        .cloned()
+        .ok_or("change_kind missing")
+        .and_then(|kind| bson::from_bson(kind).map_err(|_| "invalid change_kind"))?;
+    let changed_at = match document.get("changed_at") {
+        Some(Bson::DateTime(changed_at)) => changed_at.timestamp_millis() / 1000,
+        Some(Bson::Int64(changed_at)) => *changed_at,
+        _ => return Err("changed_at missing".to_owned()),
+    };
+    let artwork = match kind {
+        ChangeKind::Hidden => None,
+        _ => Some(parse_artwork(document.clone()).map_err(|invalid| invalid.error)?),
+    };
+    let art_id = match document.get("art_id") {
+        Some(Bson::Int32(art_id)) => i64::from(*art_id),
+        Some(Bson::Int64(art_id)) => *art_id,
+        _ => return Err("art_id missing".to_owned()),
+    };
+    Ok(ArtworkChange {
+        change,
+        kind,
+        art_id,
+        changed_at,
+        artwork,
+    })
+}
+
+/// A registered webhook target. `events` lists the content changes it receives,
+/// `secret` keys the signature of every delivery
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Webhook {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub url: String,
+    pub events: Vec<ContentEvent>,
+    pub secret: String,
+    pub created_at: i64,
+}
+
+/// Outcome of a webhook delivery
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Not delivered yet, more attempts will follow
+    Pending,
+    Delivered,
+    /// Given up after a permanent error or the last attempt
+    Failed,
+}
+
+/// One POST of a webhook delivery
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeliveryAttempt {
+    /// Unix timestamp (seconds) of the request
+    pub at: i64,
+    /// Response status, none if no response was received
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// A delivery log entry: the changes of one db sync sent to one webhook
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebhookDelivery {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub webhook_id: String,
+    pub url: String,
+    pub events: Vec<ContentEvent>,
+    pub art_ids: Vec<i64>,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+    pub created_at: i64,
+}
+
+/// Register a webhook
+pub async fn insert_webhook(
+    db: &Database,
+    webhook: &Webhook,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("insert_webhook");
+    let collection = db.collection::<Webhook>(WEBHOOKS_COLLECTION);
+    collection.insert_one(webhook, None).await?;
+    Ok(())
+}
+
+/// Get every registered webhook, oldest first
+pub async fn get_webhooks(db: &Database) -> Result<Vec<Webhook>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_webhooks");
+    let collection = db.collection::<Webhook>(WEBHOOKS_COLLECTION);
+    let cursor = collection
+        .find(
+            None,
+            FindOptions::builder()
+                .sort(doc! { "created_at": 1 })
+                .build(),
+        )
+        .await?;
+    let result = cursor
+        .filter_map(|item| match item {
+            Ok(webhook) => Some(webhook),
+            Err(e) => {
+                log::error!("get_webhooks cursor error {:?}", e);
+                None
+            }
+        })
+        .collect()
+        .await;
+    Ok(result)
+}
+
+/// Unregister a webhook. Returns whether it existed
+pub async fn delete_webhook(db: &Database, id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("delete_webhook");
+    let collection = db.collection::<Webhook>(WEBHOOKS_COLLECTION);
+    let result = collection.delete_one(doc! { "_id": id }, None).await?;
+    Ok(result.deleted_count > 0)
+}
+
+/// Add an entry to the delivery log
+pub async fn insert_webhook_delivery(
+    db: &Database,
+    delivery: &WebhookDelivery,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("insert_webhook_delivery");
+    let mut document = bson::to_document(delivery)?;
+    // Date typed copy of `created_at` for the expiry index
+    document.insert("logged_at", DateTime::now());
+    let collection = db.collection::<Document>(WEBHOOK_DELIVERIES_COLLECTION);
+    collection.insert_one(document, None).await?;
+    Ok(())
+}
+
+/// Append an attempt to a delivery log entry and update its status
+pub async fn record_delivery_attempt(
+    db: &Database,
+    delivery_id: &str,
+    attempt: &DeliveryAttempt,
+    status: DeliveryStatus,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("record_delivery_attempt");
+    let collection = db.collection::<Document>(WEBHOOK_DELIVERIES_COLLECTION);
+    collection
+        .update_one(
+            doc! { "_id": delivery_id },
+            doc! {
+                "$push": { "attempts": bson::to_bson(attempt)? },
+                "$set": { "status": bson::to_bson(&status)? },
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Get the latest delivery log entries of a webhook, newest first
+pub async fn get_webhook_deliveries(
+    db: &Database,
+    webhook_id: &str,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>, Box<dyn std::error::Error>> {
+    let _timer = metrics::db_timer("get_webhook_deliveries");
+    let collection = db.collection::<WebhookDelivery>(WEBHOOK_DELIVERIES_COLLECTION);
+    let cursor = collection
+        .find(
+            doc! { "webhook_id": webhook_id },
+            FindOptions::builder()
+                .sort(doc! { "created_at": -1 })
+                .limit(limit)
+                .build(),
+        )
+        .await?;
+    let result = cursor
+        .filter_map(|item| match item {
+            Ok(delivery) => Some(delivery),
+            Err(e) => {
+                log::error!("get_webhook_deliveries cursor error {:?}", e);
+                None
+            }
+        })
+        .collect()
+        .await;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        across_rating_views, artwork_write_pipeline, ids_query, is_document_visible,
+        is_upload_change, parse_artwork, parse_change, pending_filter, project_document, seed_hash,
+        seeded_order_stages, ArtworkFields, ArtworkQueryOption, ArtworkSort, ChangeKind,
+        ExportFilter,
+    };
+    use mongodb::bson::{self, doc, Bson};
+
+    #[test]
+    fn test_seeded_order_is_reproducible() {
+        assert_eq!(seed_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(
+            seeded_order_stages("2022-03-08"),
+            seeded_order_stages("2022-03-08")
+        );
+        assert_ne!(
+            seeded_order_stages("2022-03-08"),
+            seeded_order_stages("2022-03-09")
+        );
+    }
+
+    #[test]
+    fn test_document_visibility() {
+        assert!(is_document_visible(&doc! {
+            "moderate": { "type": "NSFW", "status": "PUSH" },
+        }));
+        assert!(!is_document_visible(&doc! {
+            "is_404": true,
+            "moderate": { "type": "SFW", "status": "PASS" },
+        }));
+        assert!(!is_document_visible(&doc! {
+            "moderate": { "type": "SFW", "status": "PENDING" },
+        }));
+        assert!(!is_document_visible(&doc! {}));
+    }
+
+    #[test]
+    fn test_project_document() {
+        let document = doc! {
+            "_id": 1,
+            "art_id": 96664758,
+            "title": "Ayaka",
+            "images": [
+                { "urls": { "thumb_mini": "a_mini", "small": "a_small", "original": "a" } },
+                { "urls": { "thumb_mini": "b_mini" } },
+                "not a document",
+            ],
+            "moderate": { "type": "SFW" },
+        };
+        let projection =
+            ArtworkFields::parse("images.urls.thumb_mini,images.urls.small,moderate.status,is_404")
+                .unwrap()
+                .projection()
+                .unwrap();
+        assert_eq!(
+            project_document(&document, &projection),
+            doc! {
+                "art_id": 96664758,
+                "images": [
+                    { "urls": { "thumb_mini": "a_mini", "small": "a_small" } },
+                    { "urls": { "thumb_mini": "b_mini" } },
+                ],
+                "moderate": {},
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_upload_change() {
+        let artwork = |kind: &str, status: &str| {
+            doc! {
+                "art_id": 1,
+                "moderate": { "type": "SFW", "status": status },
+                "change_seq": 5,
+                "change_kind": kind,
+            }
+        };
+        let update = |updated: bson::Document, artwork: bson::Document| {
+            doc! {
+                "operationType": "update",
+                "updateDescription": { "updatedFields": updated },
+                "fullDocument": artwork,
+            }
+        };
+        let insert = doc! { "operationType": "insert", "fullDocument": artwork("created", "PASS") };
+        assert!(is_upload_change(&insert));
+        let moderated =
+            doc! { "moderate.status": "PASS", "change_seq": 5, "change_kind": "created" };
+        assert!(is_upload_change(&update(
+            moderated.clone(),
+            artwork("created", "PASS")
+        )));
+        // Hidden again by the time the artwork was looked up
+        assert!(!is_upload_change(&update(
+            moderated,
+            artwork("hidden", "REJECT")
+        )));
+        // Stamped by the backfill
+        let backfilled = doc! { "change_seq": 5, "change_kind": "created", "changed_at": 0 };
+        assert!(!is_upload_change(&update(
+            backfilled,
+            artwork("created", "PASS")
+        )));
+        // Written again while visible, `change_kind` was `created` already
+        let rewritten = doc! { "like_count": 3, "change_seq": 6 };
+        assert!(!is_upload_change(&update(
+            rewritten,
+            artwork("created", "PASS")
+        )));
+        assert!(!is_upload_change(&doc! { "operationType": "delete" }));
+    }
+
+    #[test]
+    fn test_artwork_write_pipeline() {
+        let replacement = doc! { "art_id": 96664758_i64, "title": "$title" };
+        let stage = |visible: bool| {
+            let pipeline = artwork_write_pipeline(replacement.clone(), visible, 7).unwrap();
+            assert_eq!(pipeline.len(), 1);
+            let objects = pipeline[0]
+                .get_document("$replaceWith")
+                .unwrap()
+                .get_array("$mergeObjects")
+                .unwrap()
+                .clone();
+            assert_eq!(
+                objects[1],
+                Bson::Document(doc! { "$literal": replacement.clone() })
+            );
+            objects[2].as_document().unwrap().clone()
+        };
+
+        let visible = stage(true);
+        assert_eq!(visible.get_i64("change_seq").unwrap(), 7);
+        assert_eq!(visible.get_str("changed_at").unwrap(), "$$NOW");
+        let kinds = visible
+            .get_document("change_kind")
+            .unwrap()
+            .get_array("$cond")
+            .unwrap();
+        assert_eq!(kinds[1], Bson::from("updated"));
+        assert_eq!(kinds[2], Bson::from("created"));
+
+        let hidden = stage(false);
+        let branches = hidden.get_array("$cond").unwrap();
+        let stamped = branches[1].as_document().unwrap();
+        assert_eq!(stamped.get_i64("change_seq").unwrap(), 7);
+        assert_eq!(stamped.get_str("change_kind").unwrap(), "hidden");
+        // An artwork that was hidden already keeps its tombstone
+        assert_eq!(
+            branches[2],
+            Bson::Document(doc! {
+                "change_seq": "$change_seq",
+                "change_kind": "$change_kind",
+                "changed_at": "$changed_at",
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_change() {
+        let tombstone = doc! {
+            "art_id": 96664758_i64,
+            "change_seq": 3_i64,
+            "change_kind": "hidden",
+            "changed_at": bson::DateTime::from_millis(1_600_000_000_500),
+        };
+        let change = parse_change(3, tombstone.clone()).unwrap();
+        assert_eq!(change.kind, ChangeKind::Hidden);
+        assert_eq!(change.art_id, 96664758);
+        assert_eq!(change.changed_at, 1_600_000_000);
+        assert!(change.artwork.is_none());
+
+        let mut created = tombstone.clone();
+        created.insert("change_kind", "created");
+        assert!(parse_change(3, created).is_err());
+        let mut unknown = tombstone;
+        unknown.insert("change_kind", "moved");
+        assert!(parse_change(3, unknown).is_err());
+    }
+
+    #[test]
+    fn test_across_rating_views() {
+        let stages = vec![doc! { "$project": { "artist_id": 1 } }];
+        assert_eq!(
+            across_rating_views(stages.clone()),
+            vec![
+                doc! { "$project": { "artist_id": 1 } },
+                doc! { "$unionWith": { "coll": "artworks_nsfw", "pipeline": stages.clone() } },
+                doc! { "$unionWith": { "coll": "artworks_r18", "pipeline": stages } },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pending_filter() {
+        assert_eq!(
+            pending_filter(),
+            doc! {
+                "is_404": { "$ne": true },
+                "$or": [
+                    { "moderate.type": { "$nin": ["SFW", "NSFW", "R18"] } },
+                    { "moderate.status": { "$nin": ["PASS", "PUSH"] } },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_export_filter_query() {
+        assert_eq!(ExportFilter::default().query(), doc! {});
+        let filter = ExportFilter {
+            rating: Some("r18".to_owned()),
+            status: Some("pass".to_owned()),
+            since: Some(1646697600),
+            until: None,
+        };
+        assert_eq!(
+            filter.query(),
+            doc! {
+                "moderate.type": "R18",
+                "moderate.status": "PASS",
+                "upload_timestamp": { "$gte": 1646697600_i64 },
+            }
+        );
+    }
+
+    #[test]
+    fn test_normalized_options_share_a_key() {
+        let a = ArtworkQueryOption::builder()
+            .characters(vec![
+                " Keqing".to_owned(),
+                "Ganyu".to_owned(),
+                "".to_owned(),
+            ])
+            .image_type("nsfw".to_owned())
+            .build();
+        let b = ArtworkQueryOption::builder()
+            .characters(vec!["Ganyu".to_owned(), "Keqing".to_owned()])
+            .image_type("NSFW".to_owned())
+            .sort(ArtworkSort::Newest)
+            .build();
+        assert_eq!(a.normalized(), b.normalized());
+        let empty = ArtworkQueryOption::builder().characters(vec![]).build();
+        assert_eq!(empty.normalized().characters, None);
+    }
+
+    #[test]
+    fn test_ids_query_upload_range() {
+        let options = ArtworkQueryOption::builder()
+            .characters(vec!["Ganyu".to_owned()])
+            .since(1646092800)
+            .until(1646697600)
+            .build();
+        let (collection_name, pipeline) = ids_query(Some(options));
+        assert_eq!(collection_name, "artworks_sfw");
+        assert_eq!(
+            pipeline[0],
+            doc! { "$match": {
+                "$or": [{ "characters": { "$regex": "Ganyu", "$options": "i" } }],
+                "upload_timestamp": { "$gte": 1646092800_i64, "$lt": 1646697600_i64 },
+            }}
+        );
+    }
+
+    #[test]
+    fn test_ids_query_engagement_thresholds() {
+        let options = ArtworkQueryOption::builder()
+            .min_likes(100)
+            .min_views(0)
+            .build();
+        let (_, pipeline) = ids_query(Some(options));
+        assert_eq!(
+            pipeline[0],
+            doc! { "$match": { "like_count": { "$gte": 100 } } }
+        );
+    }
+
+    #[test]
+    fn test_ids_query_sort_and_paging() {
+        let options = ArtworkQueryOption::builder()
+            .image_type("NSFW".to_owned())
+            .sort(ArtworkSort::Likes)
+            .offset(20)
+            .limit(10)
+            .build();
+        let (collection_name, pipeline) = ids_query(Some(options));
+        assert_eq!(collection_name, "artworks_nsfw");
+        assert_eq!(
+            pipeline,
+            vec![
+                doc! { "$match": {} },
+                doc! { "$sort": { "like_count": -1, "art_id": -1 } },
+                doc! { "$skip": 20_i64 },
+                doc! { "$limit": 10_i64 },
+                doc! { "$project": { "art_id": 1 } },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ids_query_random_pages_share_the_seeded_order() {
+        let page = |offset: u64| {
+            let options = ArtworkQueryOption::builder()
+                .sort(ArtworkSort::Random)
+                .seed("mirror".to_owned())
+                .offset(offset)
+                .limit(10)
+                .build();
+            ids_query(Some(options.normalized())).1
+        };
+        let order = seeded_order_stages("mirror");
+        assert_eq!(page(0)[1..3], order[..]);
+        assert_eq!(page(10)[1..3], order[..]);
+        assert_eq!(page(10)[3], doc! { "$skip": 10_i64 });
+
+        let options = ArtworkQueryOption::builder()
+            .sort(ArtworkSort::Random)
+            .build();
+        assert!(options.normalized().seed.is_some());
+        let options = ArtworkQueryOption::builder()
+            .seed("unused".to_owned())
+            .build();
+        assert_eq!(options.normalized().seed, None);
+    }
+
+    #[test]
+    fn test_ids_query_clamps_huge_paging() {
+        let options = ArtworkQueryOption::builder()
+            .offset(u64::MAX)
+            .limit(u64::MAX)
+            .build();
+        let (_, pipeline) = ids_query(Some(options));
+        assert_eq!(pipeline[2], doc! { "$skip": i64::MAX });
+        assert_eq!(pipeline[3], doc! { "$limit": i64::MAX });
+    }
+
+    #[test]
+    fn test_artwork_fields_presets() {
+        assert_eq!(ArtworkFields::parse("full").unwrap().projection(), None);
+        assert_eq!(
+            ArtworkFields::parse("thumb").unwrap().projection(),
+            Some(doc! {
+                "_id": 0,
+                "art_id": 1,
+                "title": 1,
+                "artist_id": 1,
+                "images.urls.thumb_mini": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_artwork_fields_custom() {
+        assert_eq!(
+            ArtworkFields::parse("images.urls.small, images,title")
+                .unwrap()
+                .projection(),
+            Some(doc! { "_id": 0, "art_id": 1, "images": 1, "title": 1 })
+        );
+        assert!(ArtworkFields::parse("title,$where").is_err());
+    }
+
+    #[test]
+    fn test_parse_artwork_reports_invalid_document() {
+        let invalid = parse_artwork(doc! { "art_id": 42_i64, "title": 1 }).unwrap_err();
+        assert_eq!(invalid.art_id, Some(42));
+        assert!(!invalid.error.is_empty());
+    }
+
+    #[test]
+    fn test_parse_artwork_accepts_int32_art_id() {
+        let artwork = parse_artwork(doc! {
+            "art_id": 42_i32,
+            "title": "",
+            "tag_str": "",
+            "characters": ["Ganyu"],
+            "view_count": 0,
+            "like_count": 0,
+            "love_count": 0,
+            "artist_id": 1_i64,
+            "upload_timestamp": 0_i64,
+        })
+        .unwrap();
+        assert_eq!(artwork.art_id, 42);
     }
 }
@@ -0,0 +1,152 @@
+use std::fmt;
+
+/// Content type of delta encoded id listings
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// DeltaEncoder writes artwork ids in the compact binary listing format.
+///
+/// Every id is stored as its difference to the previous id (the first one to 0),
+/// zigzag mapped so that small negative differences stay small, then written as an
+/// unsigned LEB128 varint: 7 bits per byte, least significant group first, with the
+/// high bit set on every byte but the last. Ids sorted by upload time are close to each
+/// other, so most of them take 2 or 3 bytes instead of 8.
+///
+/// Differences wrap around on overflow, so every `i64` round trips through `decode`
+pub struct DeltaEncoder {
+    previous: i64,
+}
+
+impl DeltaEncoder {
+    pub fn new() -> Self {
+        DeltaEncoder { previous: 0 }
+    }
+
+    /// Appends the encoding of the next id to `buffer`
+    pub fn push(&mut self, art_id: i64, buffer: &mut Vec<u8>) {
+        let mut value = zigzag(art_id.wrapping_sub(self.previous));
+        self.previous = art_id;
+        while value >= 0x80 {
+            buffer.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        buffer.push(value as u8);
+    }
+}
+
+impl Default for DeltaEncoder {
+    fn default() -> Self {
+        DeltaEncoder::new()
+    }
+}
+
+/// Encodes a whole id listing
+pub fn encode(id_list: &[i64]) -> Vec<u8> {
+    let mut encoder = DeltaEncoder::new();
+    let mut buffer = Vec::with_capacity(id_list.len() * 3);
+    for art_id in id_list {
+        encoder.push(*art_id, &mut buffer);
+    }
+    buffer
+}
+
+/// Error decoding a delta encoded id listing
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The input ends in the middle of a varint
+    Truncated,
+    /// A varint is longer than 10 bytes or exceeds 64 bits
+    Overflow { offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "input ends in the middle of a varint"),
+            DecodeError::Overflow { offset } => {
+                write!(f, "varint at byte {} exceeds 64 bits", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes a listing written by `DeltaEncoder` back into artwork ids, in order.
+/// Clients in other languages can port this function as is
+pub fn decode(bytes: &[u8]) -> Result<Vec<i64>, DecodeError> {
+    let mut id_list = vec![];
+    let mut previous: i64 = 0;
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let start = offset;
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *bytes.get(offset).ok_or(DecodeError::Truncated)?;
+            offset += 1;
+            let group = (byte & 0x7f) as u64;
+            if shift == 63 && group > 1 || shift > 63 {
+                return Err(DecodeError::Overflow { offset: start });
+            }
+            value |= group << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        previous = previous.wrapping_add(unzigzag(value));
+        id_list.push(previous);
+    }
+    Ok(id_list)
+}
+
+/// Maps signed to unsigned integers so that values close to 0 stay small: 0, -1, 1, -2, ...
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, zigzag, DecodeError};
+
+    #[test]
+    fn test_round_trip() {
+        let id_list = vec![
+            96664758,
+            96646484,
+            96635504,
+            96635505,
+            1,
+            i64::MAX,
+            i64::MIN,
+            0,
+        ];
+        assert_eq!(decode(&encode(&id_list)).unwrap(), id_list);
+        assert_eq!(decode(&[]).unwrap(), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_encoding_is_compact() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        // 300 encodes as zigzag 600 = 0b100_1011000
+        assert_eq!(encode(&[300]), vec![0xd8, 0x04]);
+        // Ids sorted by upload time differ by a few thousand at most
+        let id_list: Vec<i64> = (0..1000).map(|n| 96664758 - n * 1500).collect();
+        assert!(encode(&id_list).len() < 2 * id_list.len() + 8);
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_input() {
+        assert_eq!(decode(&[0x80]), Err(DecodeError::Truncated));
+        assert_eq!(
+            decode(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]),
+            Err(DecodeError::Overflow { offset: 1 })
+        );
+    }
+}
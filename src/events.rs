@@ -0,0 +1,192 @@
+use crate::artwork::ArtworkInfo;
+use crate::character;
+use crate::db::{
+    change_streams_unsupported, command_error_code, is_upload_change, watch_uploads, SavedArtwork,
+};
+use mongodb::bson::{self, Document};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_stream::StreamExt;
+
+/// Delay before a change stream that ended or failed to open is opened again
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An artwork that just became visible
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UploadEvent {
+    pub art_id: i64,
+    pub characters: Vec<String>,
+    /// `SFW`, `NSFW` or `R18`
+    pub rating: String,
+}
+
+impl UploadEvent {
+    /// The event of a saved artwork, if the save made it visible
+    pub fn from_saved(saved: &SavedArtwork) -> Option<UploadEvent> {
+        if !saved.became_visible() {
+            return None;
+        }
+        UploadEvent::from_artwork(&saved.artwork)
+    }
+
+    /// The event of a change stream event of `watch_uploads`, if the write made it visible
+    pub fn from_change(change: &Document) -> Option<UploadEvent> {
+        if !is_upload_change(change) {
+            return None;
+        }
+        let artwork = change.get_document("fullDocument").ok()?.clone();
+        match bson::from_document::<ArtworkInfo>(artwork) {
+            Ok(artwork) => UploadEvent::from_artwork(&artwork),
+            Err(e) => {
+                log::warn!("Invalid artwork in change stream {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn from_artwork(artwork: &ArtworkInfo) -> Option<UploadEvent> {
+        Some(UploadEvent {
+            art_id: artwork.art_id,
+            characters: artwork.characters.clone(),
+            rating: artwork.moderate.as_ref()?.art_type.clone()?,
+        })
+    }
+}
+
+/// UploadEvents broadcasts newly visible artworks to the subscribers of this process.
+///
+/// Where the deployment supports change streams (replica sets and sharded clusters), every
+/// process broadcasts the artworks a change stream on `artworks` reports, so subscribers of
+/// any replica get the writes of every db sync. Otherwise events are broadcast by the process
+/// whose db sync wrote the artworks
+pub struct UploadEvents {
+    sender: broadcast::Sender<UploadEvent>,
+    /// Set once a change stream feeds the broadcast, see `run_watch`
+    watching: AtomicBool,
+}
+
+impl UploadEvents {
+    /// Subscribers lagging more than `capacity` events behind miss the oldest ones
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        UploadEvents {
+            sender,
+            watching: AtomicBool::new(false),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UploadEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes events written by a db sync of this process, unless the change stream
+    /// reports them
+    pub fn publish(&self, events: Vec<UploadEvent>) {
+        if self.watching.load(Ordering::SeqCst) {
+            return;
+        }
+        for event in events {
+            // Only fails when nobody is subscribed
+            let _ = self.sender.send(event);
+        }
+    }
+
+    /// Broadcasts the uploads reported by a change stream on `artworks`, forever.
+    /// A stream that ends is resumed after its last event. Returns right away if the
+    /// deployment doesn't support change streams, leaving `publish` to broadcast
+    pub async fn run_watch(&self, db: Database) {
+        let mut resume_after: Option<Document> = None;
+        loop {
+            match watch_uploads(&db, resume_after.clone()).await {
+                Ok(mut cursor) => {
+                    self.watching.store(true, Ordering::SeqCst);
+                    while let Some(change) = cursor.next().await {
+                        let change = match change {
+                            Ok(change) => change,
+                            Err(e) => {
+                                log::warn!("Watch uploads {:?}", e);
+                                break;
+                            }
+                        };
+                        // The event id is its resume token
+                        resume_after = change.get_document("_id").ok().cloned();
+                        if let Some(event) = UploadEvent::from_change(&change) {
+                            let _ = self.sender.send(event);
+                        }
+                    }
+                }
+                Err(e) if change_streams_unsupported(e.as_ref()) => {
+                    log::info!("Change streams are unsupported, uploads are broadcast in process");
+                    return;
+                }
+                Err(e) => {
+                    log::warn!("Watch uploads {:?}", e);
+                    // The server refused to resume, e.g. since its history moved past the
+                    // token, so the stream restarts from now
+                    if command_error_code(e.as_ref()).is_some() {
+                        resume_after = None;
+                    }
+                }
+            }
+            tokio::time::sleep(WATCH_RETRY_DELAY).await;
+        }
+    }
+}
+
+/// Optional filters of an upload stream
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct UploadFilter {
+    /// `SFW`, `NSFW` or `R18`, ignoring case
+    pub rating: Option<String>,
+    /// A character name or alias, or part of a name
+    pub character: Option<String>,
+}
+
+impl UploadFilter {
+    pub fn matches(&self, event: &UploadEvent) -> bool {
+        if let Some(rating) = &self.rating {
+            if !event.rating.eq_ignore_ascii_case(rating.trim()) {
+                return false;
+            }
+        }
+        match self.character.as_deref().map(str::trim) {
+            Some(name) if !name.is_empty() => match character::find(name) {
+                Some(meta) => event.characters.iter().any(|chara| meta.matches(chara)),
+                None => {
+                    let name = name.to_lowercase();
+                    event
+                        .characters
+                        .iter()
+                        .any(|chara| chara.to_lowercase().contains(&name))
+                }
+            },
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UploadEvent, UploadFilter};
+
+    #[test]
+    fn test_upload_filter() {
+        let event = UploadEvent {
+            art_id: 96664758,
+            characters: vec!["KamisatoAyaka".to_owned(), "Ganyu".to_owned()],
+            rating: "SFW".to_owned(),
+        };
+        let filter = |rating: Option<&str>, character: Option<&str>| UploadFilter {
+            rating: rating.map(str::to_owned),
+            character: character.map(str::to_owned),
+        };
+        assert!(filter(None, None).matches(&event));
+        assert!(filter(Some("sfw"), Some("Ayaka")).matches(&event));
+        assert!(filter(None, Some("gan")).matches(&event));
+        assert!(!filter(Some("R18"), None).matches(&event));
+        assert!(!filter(None, Some("Keqing")).matches(&event));
+    }
+}
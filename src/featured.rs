@@ -0,0 +1,147 @@
+use crate::artwork::ArtworkInfo;
+use crate::db::{
+    get_featured_slots, get_ids, get_visible_artwork_info_by_ids, save_featured_slot, seed_hash,
+    ArtworkQueryOption, ArtworkSort, FeaturedSlot,
+};
+use chrono::{Duration, NaiveDate, Utc};
+use mongodb::Database;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Date format of featured slots
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Most days returned by a single featured range query
+pub const FEATURED_MAX_RANGE_DAYS: i64 = 31;
+
+/// Fallback picks are drawn from the most liked SFW artworks
+const FALLBACK_CANDIDATES: u64 = 100;
+
+/// The artwork featured on a date
+#[derive(Clone, Debug, Serialize)]
+pub struct FeaturedArtwork {
+    pub date: String,
+    pub source: String,
+    pub artwork: ArtworkInfo,
+}
+
+/// Today's date (UTC)
+pub fn today() -> NaiveDate {
+    Utc::now().naive_utc().date()
+}
+
+/// Parses a `YYYY-MM-DD` date
+pub fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date.trim(), DATE_FORMAT).ok()
+}
+
+/// Picks the fallback artwork of a date. The pick only depends on the date and the candidates
+fn fallback_pick(date: &str, candidates: &[i64]) -> Option<i64> {
+    if candidates.is_empty() {
+        return None;
+    }
+    let index = seed_hash(date) % candidates.len() as u64;
+    Some(candidates[index as usize])
+}
+
+/// Resolves the featured artwork of every date between `from` and `to`, inclusive.
+/// Dates without a scheduled slot (or whose scheduled artwork is no longer a visible SFW one)
+/// get a fallback pick, which is saved for today so that it stays the same for the rest of the day
+pub async fn get_featured(
+    db: &Database,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<FeaturedArtwork>, Box<dyn std::error::Error>> {
+    let from_str = from.format(DATE_FORMAT).to_string();
+    let to_str = to.format(DATE_FORMAT).to_string();
+    let slots: HashMap<String, FeaturedSlot> = get_featured_slots(db, &from_str, &to_str)
+        .await?
+        .into_iter()
+        .map(|slot| (slot.date.clone(), slot))
+        .collect();
+    let candidate_options = ArtworkQueryOption::builder()
+        .image_type("SFW".to_owned())
+        .sort(ArtworkSort::Likes)
+        .limit(FALLBACK_CANDIDATES)
+        .build();
+    let candidates = get_ids(db, candidate_options).await?;
+
+    let mut dates = vec![];
+    let mut date = from;
+    while date <= to {
+        dates.push(date.format(DATE_FORMAT).to_string());
+        date += Duration::days(1);
+    }
+    let mut id_list: Vec<i64> = slots.values().map(|slot| slot.art_id).collect();
+    id_list.extend(
+        dates
+            .iter()
+            .filter_map(|date| fallback_pick(date, &candidates)),
+    );
+    id_list.sort_unstable();
+    id_list.dedup();
+    // Scheduled artworks that were re-rated or taken down since are left out
+    let artworks: HashMap<i64, ArtworkInfo> = get_visible_artwork_info_by_ids(db, "SFW", id_list)
+        .await?
+        .artworks
+        .into_iter()
+        .map(|artwork| (artwork.art_id, artwork))
+        .collect();
+
+    let today = today().format(DATE_FORMAT).to_string();
+    let mut result = vec![];
+    for date in dates {
+        let scheduled = slots
+            .get(&date)
+            .and_then(|slot| artworks.get(&slot.art_id).map(|art| (slot, art)));
+        if let Some((slot, artwork)) = scheduled {
+            result.push(FeaturedArtwork {
+                date,
+                source: slot.source.clone(),
+                artwork: artwork.clone(),
+            });
+            continue;
+        }
+        let fallback = fallback_pick(&date, &candidates).and_then(|art_id| artworks.get(&art_id));
+        if let Some(artwork) = fallback {
+            if date == today && !slots.contains_key(&date) {
+                let slot = FeaturedSlot {
+                    date: date.clone(),
+                    art_id: artwork.art_id,
+                    source: "auto".to_owned(),
+                };
+                if let Err(e) = save_featured_slot(db, &slot, false).await {
+                    log::warn!("Save fallback featured slot {} {:?}", date, e);
+                }
+            }
+            result.push(FeaturedArtwork {
+                date,
+                source: "auto".to_owned(),
+                artwork: artwork.clone(),
+            });
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fallback_pick, parse_date};
+
+    #[test]
+    fn test_fallback_pick_is_deterministic() {
+        let candidates = vec![1, 2, 3, 4, 5];
+        assert_eq!(
+            fallback_pick("2022-03-08", &candidates),
+            fallback_pick("2022-03-08", &candidates)
+        );
+        assert_eq!(fallback_pick("2022-03-08", &[]), None);
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert!(parse_date("2022-03-08").is_some());
+        assert!(parse_date("2022-13-08").is_none());
+        assert!(parse_date("03/08/2022").is_none());
+    }
+}
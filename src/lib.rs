@@ -1,3 +1,14 @@
 pub mod api;
 pub mod artwork;
+pub mod cache;
+pub mod character;
+pub mod conditional;
 pub mod db;
+pub mod delta;
+pub mod events;
+pub mod featured;
+pub mod logging;
+pub mod metrics;
+pub mod ndjson;
+pub mod ratelimit;
+pub mod webhooks;
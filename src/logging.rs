@@ -0,0 +1,181 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use env_logger::Env;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde_json::json;
+use std::io::Write;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Format of the access log line of `actix_web::middleware::Logger`. It ends with the request
+/// id, since the line is written after the request's task is done with it
+pub const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{X-Request-Id}o"#;
+
+/// Target of the access log records
+const ACCESS_LOG_TARGET: &str = "actix_web::middleware::logger";
+
+/// Longest client supplied request id that is accepted as is
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Returns the id of the request being served by the current task, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Initializes the global logger.
+/// `LOG_FORMAT=json` emits one json object per line, anything else emits plain text.
+/// Both formats carry the request id when logging from within a request
+pub fn init_from_env() {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    let json_format = std::env::var("LOG_FORMAT")
+        .map(|format| format.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+    if json_format {
+        builder.format(|buf, record| {
+            let message = record.args().to_string();
+            let request_id = current_request_id().or_else(|| {
+                if record.target() != ACCESS_LOG_TARGET {
+                    return None;
+                }
+                access_log_request_id(&message).map(str::to_owned)
+            });
+            let line = json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": message,
+                "request_id": request_id,
+            });
+            writeln!(buf, "{}", line)
+        });
+    } else {
+        builder.format(|buf, record| {
+            let level_style = buf.default_level_style(record.level());
+            write!(
+                buf,
+                "[{} {:<5} {}] ",
+                buf.timestamp(),
+                level_style.value(record.level()),
+                record.target(),
+            )?;
+            if let Some(request_id) = current_request_id() {
+                write!(buf, "[{}] ", request_id)?;
+            }
+            writeln!(buf, "{}", record.args())
+        });
+    }
+    builder.init();
+}
+
+/// Reads the request id off an access log line, see `ACCESS_LOG_FORMAT`
+fn access_log_request_id(line: &str) -> Option<&str> {
+    let (_, request_id) = line.rsplit_once(" request_id=")?;
+    // `Logger` writes `-` for a missing header
+    Some(request_id).filter(|request_id| !request_id.is_empty() && *request_id != "-")
+}
+
+/// Accepts a client supplied request id only if it is short and printable
+fn sanitize_request_id(value: &HeaderValue) -> Option<String> {
+    let value = value.to_str().ok()?.trim();
+    if value.is_empty()
+        || value.len() > MAX_REQUEST_ID_LEN
+        || !value.chars().all(|c| c.is_ascii_graphic())
+    {
+        return None;
+    }
+    Some(value.to_owned())
+}
+
+/// RequestId middleware assigns every request an id (reusing a valid incoming `X-Request-Id`),
+/// makes it available to log records emitted while serving the request,
+/// and echoes it back in the `X-Request-Id` response header
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(sanitize_request_id)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let header_value = HeaderValue::from_str(&request_id).expect("request id is ascii");
+        req.headers_mut().insert(
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            header_value.clone(),
+        );
+        let fut = REQUEST_ID.scope(request_id, self.service.call(req));
+        Box::pin(async move {
+            let mut res = fut.await?;
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), header_value);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{access_log_request_id, sanitize_request_id};
+    use actix_web::http::header::HeaderValue;
+
+    #[test]
+    fn test_sanitize_request_id() {
+        assert_eq!(
+            sanitize_request_id(&HeaderValue::from_static("abc-123")),
+            Some("abc-123".to_owned())
+        );
+        assert_eq!(sanitize_request_id(&HeaderValue::from_static("")), None);
+        assert_eq!(sanitize_request_id(&HeaderValue::from_static("a b")), None);
+        assert_eq!(
+            sanitize_request_id(&HeaderValue::from_str(&"a".repeat(200)).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_access_log_request_id() {
+        let line = r#"127.0.0.1 "GET /api/health HTTP/1.1" 200 15 "-" "curl/7.81.0" 0.000512 request_id=abc-123"#;
+        assert_eq!(access_log_request_id(line), Some("abc-123"));
+        assert_eq!(access_log_request_id("... 0.000512 request_id=-"), None);
+        assert_eq!(access_log_request_id("Starting 8 workers"), None);
+    }
+}
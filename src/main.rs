@@ -1,17 +1,27 @@
-use actix_web::middleware::Logger;
+use actix_web::middleware::{Compress, Logger};
 use actix_web::web::Data;
 use actix_web::{App, HttpServer};
-use env_logger::Env;
 use genshin_gallery_api::api::{
-    api_all, api_character_ids, api_db_sync, api_health, api_image_info, api_statistics,
-    DbSyncToken,
+    api_admin_delete_webhook, api_admin_export, api_admin_register_webhook,
+    api_admin_schedule_featured, api_admin_unschedule_featured, api_admin_validate,
+    api_admin_webhook_deliveries, api_admin_webhooks, api_all, api_changes, api_character_ids,
+    api_character_list, api_character_related, api_db_sync, api_db_sync_import, api_featured,
+    api_featured_today, api_health, api_image_info, api_image_info_batch, api_metrics, api_random,
+    api_statistics, api_stream_uploads, api_trending, ApiCache, ApiConfig, DbSyncToken,
 };
-use genshin_gallery_api::db::{create_client, create_indexes, create_views};
+use genshin_gallery_api::conditional::ConditionalGet;
+use genshin_gallery_api::db::{backfill_change_seqs, create_client, create_indexes, create_views};
+use genshin_gallery_api::events::UploadEvents;
+use genshin_gallery_api::logging::{self, RequestId, ACCESS_LOG_FORMAT};
+use genshin_gallery_api::metrics::RequestMetrics;
+use genshin_gallery_api::ratelimit::{RateLimit, RateLimitConfig};
+use genshin_gallery_api::webhooks::{RetryPolicy, Webhooks};
 use std::env;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
+    logging::init_from_env();
 
     // Read environment variables
     let conn_str = env::var("MONGODB_URL").expect("Environment variable MONGODB_URL is not set");
@@ -20,7 +30,18 @@ async fn main() -> std::io::Result<()> {
         .unwrap_or_else(|_| "8000".to_owned())
         .parse::<u16>()
         .unwrap_or(8000);
-    let db_sync_token = env::var("DB_SYNC_TOKEN").unwrap_or_default(); // not safe
+    let db_sync_token = env::var("DB_SYNC_TOKEN").unwrap_or_default();
+    if db_sync_token.is_empty() {
+        log::warn!("DB_SYNC_TOKEN is not set, db sync and admin endpoints are disabled");
+    }
+    let api_config = ApiConfig::builder()
+        .image_info_max_ids(
+            env::var("IMAGE_INFO_MAX_IDS")
+                .ok()
+                .and_then(|val| val.parse::<usize>().ok())
+                .unwrap_or(1000),
+        )
+        .build();
 
     // Connect to mongodb
     let client = create_client(conn_str.as_str()).await.unwrap();
@@ -31,19 +52,86 @@ async fn main() -> std::io::Result<()> {
     if let Err(e) = create_views(&db).await {
         log::warn!("Create views {:?}", e);
     }
+    {
+        // Artworks saved before the changes feed existed get their change numbers
+        let db = db.clone();
+        actix_web::rt::spawn(async move {
+            match backfill_change_seqs(&db).await {
+                Ok(0) => {}
+                Ok(stamped) => log::info!("Backfilled change numbers of {} artworks", stamped),
+                Err(e) => log::warn!("Backfill change numbers {:?}", e),
+            }
+        });
+    }
+
+    // Shared by every worker
+    let env_u64 = |name: &str, default: u64| {
+        env::var(name)
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(default)
+    };
+    let api_cache = Data::new(ApiCache::new(
+        Duration::from_secs(env_u64("RESPONSE_CACHE_TTL_SECS", 30)),
+        Duration::from_secs(env_u64("RELATED_CACHE_TTL_SECS", 600)),
+        env_u64("RESPONSE_CACHE_CAPACITY", 1000) as usize,
+        env_u64("RESPONSE_CACHE_MAX_BYTES", 64 << 20) as usize,
+    ));
+    let upload_events = Data::new(UploadEvents::new(1024));
+    {
+        // Broadcasts the uploads of every replica's db sync where change streams are supported
+        let upload_events = upload_events.clone();
+        let db = db.clone();
+        actix_web::rt::spawn(async move { upload_events.run_watch(db).await });
+    }
+    let webhooks = Data::new(
+        Webhooks::new(RetryPolicy::from_env()).expect("Failed to create webhook http client"),
+    );
+    let conditional_get = ConditionalGet::from_env();
+    let rate_limit =
+        RateLimit::new(RateLimitConfig::from_env().expect("Invalid rate limit config"));
 
     // Launch http webserver
     HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(Compress::default())
+            .wrap(conditional_get.clone())
+            .wrap(rate_limit.clone())
+            .wrap(RequestMetrics)
+            .wrap(RequestId)
+            .wrap(Logger::new(ACCESS_LOG_FORMAT))
             .app_data(Data::new(db.clone()))
             .app_data(Data::new(DbSyncToken::new(db_sync_token.to_owned())))
+            .app_data(Data::new(api_config.clone()))
+            .app_data(api_cache.clone())
+            .app_data(upload_events.clone())
+            .app_data(webhooks.clone())
             .service(api_health)
             .service(api_statistics)
             .service(api_all)
             .service(api_character_ids)
+            .service(api_character_related)
+            .service(api_character_list)
             .service(api_image_info)
+            .service(api_image_info_batch)
+            .service(api_trending)
+            .service(api_changes)
+            .service(api_random)
+            .service(api_featured_today)
+            .service(api_featured)
+            .service(api_admin_schedule_featured)
+            .service(api_admin_unschedule_featured)
+            .service(api_admin_register_webhook)
+            .service(api_admin_webhooks)
+            .service(api_admin_delete_webhook)
+            .service(api_admin_webhook_deliveries)
+            // Takes the ndjson syncs before api_db_sync, which would reject their body
+            .service(api_db_sync_import)
             .service(api_db_sync)
+            .service(api_metrics)
+            .service(api_stream_uploads)
+            .service(api_admin_validate)
+            .service(api_admin_export)
     })
     .bind(format!("{}:{}", server_host, server_port))?
     .run()
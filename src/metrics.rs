@@ -0,0 +1,274 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Bytes;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Registry holding every metric exposed on `/metrics`
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+static HTTP_REQUESTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("http_requests_total", "Number of http requests handled"),
+        &["method", "route", "status"],
+    ))
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "Http request latency in seconds",
+        ),
+        &["method", "route"],
+    ))
+});
+
+static DB_OPERATION_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "db_operation_duration_seconds",
+            "Database operation latency in seconds",
+        ),
+        &["operation"],
+    ))
+});
+
+static SYNC_UPSERTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "sync_upserts_total",
+            "Number of artwork upserts from db sync",
+        ),
+        &["outcome"],
+    ))
+});
+
+static ARTWORK_COUNT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new("artwork_count", "Number of artworks per rating"),
+        &["rating"],
+    ))
+});
+
+static CACHE_LOOKUPS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("cache_lookups_total", "Number of in-process cache lookups"),
+        &["cache", "outcome"],
+    ))
+});
+
+static RATE_LIMITED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "rate_limited_requests_total",
+            "Number of requests rejected by rate limiting",
+        ),
+        &["route"],
+    ))
+});
+
+static WEBHOOK_ATTEMPTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "webhook_delivery_attempts_total",
+            "Number of webhook delivery attempts",
+        ),
+        &["outcome"],
+    ))
+});
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(
+    collector: prometheus::Result<T>,
+) -> T {
+    let collector = collector.expect("metric definition is valid");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric is registered once");
+    collector
+}
+
+/// Starts timing a database operation. The duration is recorded when the timer is dropped
+pub fn db_timer(operation: &str) -> HistogramTimer {
+    DB_OPERATION_DURATION
+        .with_label_values(&[operation])
+        .start_timer()
+}
+
+/// Counts the outcome of a single artwork upsert from db sync
+pub fn record_sync_upsert(success: bool) {
+    let outcome = if success { "success" } else { "failure" };
+    SYNC_UPSERTS_TOTAL.with_label_values(&[outcome]).inc();
+}
+
+/// Sets the artwork count gauge of a rating, e.g. `sfw` or `total`
+pub fn set_artwork_count(rating: &str, count: u64) {
+    ARTWORK_COUNT
+        .with_label_values(&[rating])
+        .set(count.try_into().unwrap_or(i64::MAX));
+}
+
+/// Counts a lookup of an in-process cache as a hit or a miss
+pub fn record_cache_lookup(cache: &str, hit: bool) {
+    let outcome = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS_TOTAL
+        .with_label_values(&[cache, outcome])
+        .inc();
+}
+
+/// Counts a request rejected by rate limiting
+pub fn record_rate_limited(route: &str) {
+    RATE_LIMITED_TOTAL.with_label_values(&[route]).inc();
+}
+
+/// Counts a webhook delivery attempt, `outcome` is `delivered`, `retry` or `failed`
+pub fn record_webhook_attempt(outcome: &str) {
+    WEBHOOK_ATTEMPTS_TOTAL.with_label_values(&[outcome]).inc();
+}
+
+/// Renders all metrics in the prometheus text exposition format
+pub fn render() -> Result<String, Box<dyn std::error::Error>> {
+    Lazy::force(&HTTP_REQUESTS_TOTAL);
+    Lazy::force(&HTTP_REQUEST_DURATION);
+    Lazy::force(&DB_OPERATION_DURATION);
+    Lazy::force(&SYNC_UPSERTS_TOTAL);
+    Lazy::force(&ARTWORK_COUNT);
+    Lazy::force(&CACHE_LOOKUPS_TOTAL);
+    Lazy::force(&RATE_LIMITED_TOTAL);
+    Lazy::force(&WEBHOOK_ATTEMPTS_TOTAL);
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// RequestMetrics middleware records the count and latency of every request,
+/// labelled by the matched route pattern to keep cardinality bounded.
+/// Latency is recorded once the response body is sent (or dropped), so that streamed
+/// responses include the time spent streaming
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<TimedBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<TimedBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let method = req.method().to_string();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let timer = HTTP_REQUEST_DURATION
+            .with_label_values(&[&method, &route])
+            .start_timer();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let result = fut.await;
+            let status = match &result {
+                Ok(res) => res.status().as_u16().to_string(),
+                Err(e) => e.as_response_error().status_code().as_u16().to_string(),
+            };
+            HTTP_REQUESTS_TOTAL
+                .with_label_values(&[&method, &route, &status])
+                .inc();
+            // Errors have no body to wait for, so their latency is recorded right away
+            result.map(|res| {
+                res.map_body(|_, body| TimedBody {
+                    body: Box::pin(body),
+                    _timer: timer,
+                })
+            })
+        })
+    }
+}
+
+/// TimedBody passes a response body through, holding the request's latency timer until the
+/// body is dropped
+pub struct TimedBody<B> {
+    body: Pin<Box<B>>,
+    /// Records the latency when dropped
+    _timer: HistogramTimer,
+}
+
+impl<B: MessageBody> MessageBody for TimedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.body.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{db_timer, record_sync_upsert, render, RequestMetrics, HTTP_REQUEST_DURATION};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{body, web, App, HttpResponse};
+
+    #[test]
+    fn test_render_includes_recorded_metrics() {
+        record_sync_upsert(true);
+        drop(db_timer("get_ids"));
+        let body = render().unwrap();
+        assert!(body.contains("sync_upserts_total{outcome=\"success\"}"));
+        assert!(body.contains("db_operation_duration_seconds_count{operation=\"get_ids\"}"));
+    }
+
+    #[actix_web::test]
+    async fn test_request_latency_is_recorded_with_the_body() {
+        let app = init_service(App::new().wrap(RequestMetrics).route(
+            "/timed",
+            web::get().to(|| async { HttpResponse::Ok().body("body") }),
+        ))
+        .await;
+        let samples = || {
+            HTTP_REQUEST_DURATION
+                .with_label_values(&["GET", "/timed"])
+                .get_sample_count()
+        };
+        let res = call_service(&app, TestRequest::get().uri("/timed").to_request()).await;
+        assert_eq!(samples(), 0);
+        let bytes = body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&bytes[..], b"body");
+        assert_eq!(samples(), 1);
+    }
+}
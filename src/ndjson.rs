@@ -0,0 +1,126 @@
+/// Content type of newline-delimited json
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// A line of newline-delimited json, numbered from 1
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    /// The line without its line break
+    Data { number: usize, bytes: Vec<u8> },
+    /// The line exceeded the length limit and was dropped
+    TooLong { number: usize },
+}
+
+/// LineSplitter cuts a body arriving in chunks into lines, holding back at most one
+/// partial line. Blank lines are counted but not returned
+pub struct LineSplitter {
+    buffer: Vec<u8>,
+    number: usize,
+    max_line_bytes: usize,
+    /// The current line went over the limit, its remaining bytes are skipped
+    overflowed: bool,
+}
+
+impl LineSplitter {
+    pub fn new(max_line_bytes: usize) -> Self {
+        LineSplitter {
+            buffer: vec![],
+            number: 0,
+            max_line_bytes,
+            overflowed: false,
+        }
+    }
+
+    /// Returns the lines completed by a chunk
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Line> {
+        let mut lines = vec![];
+        for part in chunk.split_inclusive(|byte| *byte == b'\n') {
+            let (part, complete) = match part.strip_suffix(b"\n") {
+                Some(part) => (part, true),
+                None => (part, false),
+            };
+            if !self.overflowed {
+                if self.buffer.len() + part.len() > self.max_line_bytes {
+                    self.overflowed = true;
+                    self.buffer.clear();
+                } else {
+                    self.buffer.extend_from_slice(part);
+                }
+            }
+            if complete {
+                lines.extend(self.end_line());
+            }
+        }
+        lines
+    }
+
+    /// Returns the last line if the body doesn't end with a line break
+    pub fn finish(&mut self) -> Option<Line> {
+        if self.buffer.is_empty() && !self.overflowed {
+            return None;
+        }
+        self.end_line()
+    }
+
+    /// Number of lines ended so far, blank ones included
+    pub fn lines(&self) -> usize {
+        self.number
+    }
+
+    fn end_line(&mut self) -> Option<Line> {
+        self.number += 1;
+        let number = self.number;
+        if std::mem::take(&mut self.overflowed) {
+            return Some(Line::TooLong { number });
+        }
+        let mut bytes = std::mem::take(&mut self.buffer);
+        if bytes.last() == Some(&b'\r') {
+            bytes.pop();
+        }
+        if bytes.iter().all(u8::is_ascii_whitespace) {
+            return None;
+        }
+        Some(Line::Data { number, bytes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Line, LineSplitter};
+
+    fn data(number: usize, bytes: &str) -> Line {
+        Line::Data {
+            number,
+            bytes: bytes.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_lines_across_chunks() {
+        let mut splitter = LineSplitter::new(64);
+        assert_eq!(
+            splitter.push(b"{\"a\":1}\n{\"b\""),
+            vec![data(1, "{\"a\":1}")]
+        );
+        assert_eq!(splitter.push(b":2}\r\n\n"), vec![data(2, "{\"b\":2}")]);
+        assert_eq!(splitter.push(b"{\"c\":3}"), vec![]);
+        assert_eq!(splitter.finish(), Some(data(4, "{\"c\":3}")));
+        assert_eq!(splitter.lines(), 4);
+
+        let mut splitter = LineSplitter::new(64);
+        assert_eq!(splitter.push(b"{}\n"), vec![data(1, "{}")]);
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn test_long_lines_are_dropped() {
+        let mut splitter = LineSplitter::new(4);
+        assert_eq!(splitter.push(b"123"), vec![]);
+        assert_eq!(splitter.push(b"45678"), vec![]);
+        assert_eq!(
+            splitter.push(b"9\n1234\n"),
+            vec![Line::TooLong { number: 1 }, data(2, "1234")]
+        );
+        assert_eq!(splitter.push(b"12345"), vec![]);
+        assert_eq!(splitter.finish(), Some(Line::TooLong { number: 3 }));
+    }
+}
@@ -0,0 +1,554 @@
+use crate::metrics;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Header carrying the api key of a client
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Buckets are swept for idle ones every this many lookups
+const SWEEP_INTERVAL: u64 = 10_000;
+
+/// Most buckets kept. Beyond it, the least recently used tenth is dropped
+const MAX_BUCKETS: usize = 100_000;
+
+/// Number of requests allowed per period. Bursts of up to `requests` are allowed,
+/// after which requests are allowed at the average rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    /// Parses `<requests>/<seconds>`, e.g. `120/60`. `off` disables limiting
+    pub fn parse(spec: &str) -> Result<Option<Limit>, String> {
+        let spec = spec.trim();
+        if spec.eq_ignore_ascii_case("off") {
+            return Ok(None);
+        }
+        let invalid = || format!("Invalid rate limit {:?}, expected e.g. 120/60 or off", spec);
+        let (requests, seconds) = spec.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse::<u32>().map_err(|_| invalid())?;
+        let seconds = seconds.trim().parse::<u64>().map_err(|_| invalid())?;
+        if requests == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+        Ok(Some(Limit {
+            requests,
+            period: Duration::from_secs(seconds),
+        }))
+    }
+
+    /// Tokens refilled per second
+    fn rate(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// Network of trusted proxies, e.g. `10.0.0.0/8` or a single address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn parse(spec: &str) -> Result<IpNetwork, String> {
+        let invalid = || format!("Invalid ip network {:?}", spec);
+        let (addr, prefix) = match spec.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (spec.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(IpNetwork { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (network, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network) as u128, u32::from(*ip) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(*ip), 128),
+            _ => return false,
+        };
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = bits - self.prefix as u32;
+        network >> shift == ip >> shift
+    }
+}
+
+/// Configuration of the `RateLimit` middleware
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Limit of routes without their own limit, `None` for unlimited
+    pub default_limit: Option<Limit>,
+    /// Limits by route pattern, `None` for unlimited
+    pub route_limits: HashMap<String, Option<Limit>>,
+    /// Limit of every route for clients presenting a known api key
+    pub api_key_limit: Option<Limit>,
+    pub api_keys: HashSet<String>,
+    /// Proxies whose `X-Forwarded-For` header is trusted
+    pub trusted_proxies: Vec<IpNetwork>,
+}
+
+impl RateLimitConfig {
+    /// Reads the configuration from the environment:
+    /// - `RATE_LIMIT`: default limit, `120/60` unless set
+    /// - `RATE_LIMITS`: per route limits, e.g. `/api/image-info=30/60;/api/db/sync=10/60`
+    /// - `API_KEYS`: comma separated api keys, limited by `API_KEY_RATE_LIMIT` (`1200/60`)
+    /// - `TRUSTED_PROXIES`: comma separated addresses or networks, e.g. `10.0.0.0/8`
+    pub fn from_env() -> Result<Self, String> {
+        let env = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .filter(|val| !val.trim().is_empty())
+        };
+        let default_limit = Limit::parse(&env("RATE_LIMIT").unwrap_or_else(|| "120/60".into()))?;
+        let api_key_limit =
+            Limit::parse(&env("API_KEY_RATE_LIMIT").unwrap_or_else(|| "1200/60".into()))?;
+        let mut route_limits: HashMap<String, Option<Limit>> = [
+            ("/api/health", None),
+            ("/metrics", None),
+            ("/api/image-info", Limit::parse("30/60")?),
+            ("/api/db/sync", Limit::parse("10/60")?),
+        ]
+        .into_iter()
+        .map(|(route, limit)| (route.to_owned(), limit))
+        .collect();
+        for pair in env("RATE_LIMITS").unwrap_or_default().split(';') {
+            if pair.trim().is_empty() {
+                continue;
+            }
+            let (route, spec) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid RATE_LIMITS entry {:?}", pair))?;
+            route_limits.insert(route.trim().to_owned(), Limit::parse(spec)?);
+        }
+        let api_keys = env("API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|key| key.trim().to_owned())
+            .filter(|key| !key.is_empty())
+            .collect();
+        let trusted_proxies = env("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|spec| !spec.trim().is_empty())
+            .map(IpNetwork::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(RateLimitConfig {
+            default_limit,
+            route_limits,
+            api_key_limit,
+            api_keys,
+            trusted_proxies,
+        })
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+
+    /// Resolves the client address. Behind trusted proxies, it is the last
+    /// `X-Forwarded-For` entry that was not added by a trusted proxy
+    fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let peer = peer?;
+        if !self.is_trusted(&peer) {
+            return Some(peer);
+        }
+        // Read from the right, since only the entries added by trusted proxies are reliable
+        let hops: Vec<IpAddr> = match forwarded_for {
+            Some(forwarded_for) => forwarded_for
+                .rsplit(',')
+                .map_while(|hop| hop.trim().parse().ok())
+                .collect(),
+            None => return Some(peer),
+        };
+        hops.iter()
+            .find(|hop| !self.is_trusted(hop))
+            .or_else(|| hops.last())
+            .copied()
+            .or(Some(peer))
+    }
+}
+
+/// Token bucket of a client on a route
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    capacity: f64,
+    /// Tokens refilled per second
+    rate: f64,
+}
+
+impl Bucket {
+    /// Whether the bucket would be full by now, which is the same as having none
+    fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.updated).as_secs_f64() * self.rate + self.tokens >= self.capacity
+    }
+}
+
+/// Key of a client address. IPv6 clients usually get a whole /64, so they are told apart
+/// by that network rather than by address
+fn ip_client(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("ip:{}", ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => format!("ip:{}", ip),
+            None => format!(
+                "ip:{}/64",
+                Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))
+            ),
+        },
+    }
+}
+
+/// Outcome of taking a token
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Decision {
+    allowed: bool,
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the bucket is full again
+    reset: u64,
+    /// Seconds until the next request is allowed
+    retry_after: u64,
+}
+
+/// Token buckets by route and client, shared between workers
+struct Buckets {
+    buckets: HashMap<(String, String), Bucket>,
+    lookups: u64,
+    max_buckets: usize,
+}
+
+impl Buckets {
+    fn new(max_buckets: usize) -> Self {
+        Buckets {
+            buckets: HashMap::new(),
+            lookups: 0,
+            max_buckets,
+        }
+    }
+
+    fn take(&mut self, key: (String, String), limit: &Limit, now: Instant) -> Decision {
+        let capacity = limit.requests as f64;
+        let rate = limit.rate();
+        self.lookups += 1;
+        if self.lookups >= SWEEP_INTERVAL {
+            self.lookups = 0;
+            self.buckets.retain(|_, bucket| !bucket.is_idle(now));
+        }
+        if self.buckets.len() >= self.max_buckets && !self.buckets.contains_key(&key) {
+            self.evict(now);
+        }
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            capacity,
+            rate,
+        });
+        // The limit of a route may have been configured differently when the bucket was made
+        bucket.capacity = capacity;
+        bucket.rate = rate;
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Decision {
+            allowed,
+            limit: limit.requests,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after: if allowed {
+                0
+            } else {
+                ((1.0 - bucket.tokens) / rate).ceil() as u64
+            },
+        }
+    }
+
+    /// Makes room for a new bucket: drops the idle buckets, and if that's not enough,
+    /// the least recently used tenth of them
+    fn evict(&mut self, now: Instant) {
+        self.buckets.retain(|_, bucket| !bucket.is_idle(now));
+        if self.buckets.len() < self.max_buckets {
+            return;
+        }
+        let mut updated: Vec<Instant> =
+            self.buckets.values().map(|bucket| bucket.updated).collect();
+        let evicted = (updated.len() / 10).max(1);
+        let (_, cutoff, _) = updated.select_nth_unstable(evicted - 1);
+        let cutoff = *cutoff;
+        self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+    }
+}
+
+/// RateLimit middleware limits requests with token buckets per route and client.
+/// Clients are told apart by api key (`X-Api-Key`, if known) or else by address (IPv6 by /64).
+/// Every limited response carries `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset`; rejected requests get 429 with `Retry-After`
+#[derive(Clone)]
+pub struct RateLimit {
+    config: Arc<RateLimitConfig>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimit {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimit {
+            config: Arc::new(config),
+            buckets: Arc::new(Mutex::new(Buckets::new(MAX_BUCKETS))),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let config = &self.limiter.config;
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let api_key = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|key| config.api_keys.contains(*key));
+        let route_limit = config.route_limits.get(&route).copied();
+        let (client, limit) = match (api_key, route_limit) {
+            // Routes turned off are not limited for anyone
+            (_, Some(None)) => (String::new(), None),
+            (Some(api_key), _) => (format!("key:{}", api_key), config.api_key_limit),
+            (None, route_limit) => {
+                let forwarded_for = req
+                    .headers()
+                    .get("x-forwarded-for")
+                    .and_then(|value| value.to_str().ok());
+                let client = config
+                    .client_ip(req.peer_addr().map(|addr| addr.ip()), forwarded_for)
+                    .map(ip_client)
+                    .unwrap_or_else(|| "ip:unknown".to_owned());
+                (client, route_limit.unwrap_or(config.default_limit))
+            }
+        };
+        let decision = limit.map(|limit| {
+            self.limiter.buckets.lock().unwrap().take(
+                (route.clone(), client),
+                &limit,
+                Instant::now(),
+            )
+        });
+        let decision = match decision {
+            Some(decision) => decision,
+            None => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+            }
+        };
+        if !decision.allowed {
+            metrics::record_rate_limited(&route);
+            let mut res = HttpResponse::TooManyRequests()
+                .content_type("application/json")
+                .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+                .insert_header((header::RETRY_AFTER, decision.retry_after.to_string()))
+                .body(json!({ "message": "Too many requests" }).to_string());
+            insert_headers(res.headers_mut(), &decision);
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            insert_headers(res.headers_mut(), &decision);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+/// Adds the `RateLimit-*` headers of a decision
+fn insert_headers(headers: &mut header::HeaderMap, decision: &Decision) {
+    let values = [
+        ("ratelimit-limit", decision.limit as u64),
+        ("ratelimit-remaining", decision.remaining as u64),
+        ("ratelimit-reset", decision.reset),
+    ];
+    for (name, value) in values {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ip_client, Buckets, IpNetwork, Limit, RateLimitConfig};
+    use std::collections::{HashMap, HashSet};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(
+            Limit::parse("120/60").unwrap(),
+            Some(Limit {
+                requests: 120,
+                period: Duration::from_secs(60),
+            })
+        );
+        assert_eq!(Limit::parse("OFF").unwrap(), None);
+        assert!(Limit::parse("0/60").is_err());
+        assert!(Limit::parse("120").is_err());
+    }
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let limit = Limit::parse("2/10").unwrap().unwrap();
+        let mut buckets = Buckets::new(10);
+        let key = || ("/api/image-info".to_owned(), "ip:127.0.0.1".to_owned());
+        let start = Instant::now();
+        assert!(buckets.take(key(), &limit, start).allowed);
+        let decision = buckets.take(key(), &limit, start);
+        assert!(decision.allowed);
+        assert_eq!((decision.remaining, decision.reset), (0, 10));
+        let decision = buckets.take(key(), &limit, start);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, 5);
+        assert!(
+            buckets
+                .take(key(), &limit, start + Duration::from_secs(5))
+                .allowed
+        );
+        let other = ("/api/image-info".to_owned(), "ip:10.0.0.1".to_owned());
+        assert!(buckets.take(other, &limit, start).allowed);
+    }
+
+    #[test]
+    fn test_client_ip_behind_trusted_proxies() {
+        let config = RateLimitConfig {
+            default_limit: None,
+            route_limits: HashMap::new(),
+            api_key_limit: None,
+            api_keys: HashSet::new(),
+            trusted_proxies: vec![
+                IpNetwork::parse("10.0.0.0/8").unwrap(),
+                IpNetwork::parse("::1").unwrap(),
+            ],
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().ok();
+        let forwarded_for = Some("1.2.3.4, 5.6.7.8, 10.1.2.3");
+        assert_eq!(
+            config.client_ip(ip("10.0.0.1"), forwarded_for),
+            ip("5.6.7.8")
+        );
+        // Untrusted peers can't pick their address
+        assert_eq!(
+            config.client_ip(ip("9.9.9.9"), forwarded_for),
+            ip("9.9.9.9")
+        );
+        assert_eq!(config.client_ip(ip("::1"), None), ip("::1"));
+        assert_eq!(
+            config.client_ip(ip("10.0.0.1"), Some("bogus, 1.2.3.4")),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            config.client_ip(ip("10.0.0.1"), Some("10.0.0.2")),
+            ip("10.0.0.2")
+        );
+        assert!(!IpNetwork::parse("10.0.0.0/8")
+            .unwrap()
+            .contains(&"11.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ip_client() {
+        let client = |ip: &str| ip_client(ip.parse().unwrap());
+        assert_eq!(client("1.2.3.4"), "ip:1.2.3.4");
+        assert_eq!(client("::ffff:1.2.3.4"), "ip:1.2.3.4");
+        assert_eq!(client("2001:db8:1:2:3:4:5:6"), "ip:2001:db8:1:2::/64");
+        assert_eq!(client("2001:db8:1:2::9"), client("2001:db8:1:2:3:4:5:6"));
+        assert_ne!(client("2001:db8:1:3::9"), client("2001:db8:1:2::9"));
+    }
+
+    #[test]
+    fn test_buckets_are_capped() {
+        let limit = Limit::parse("2/10").unwrap().unwrap();
+        let mut buckets = Buckets::new(10);
+        let key = |client: usize| {
+            (
+                "/api/image-info".to_owned(),
+                format!("ip:10.0.0.{}", client),
+            )
+        };
+        let start = Instant::now();
+        for client in 0..10 {
+            let now = start + Duration::from_millis(client as u64);
+            assert!(buckets.take(key(client), &limit, now).allowed);
+        }
+        assert_eq!(buckets.buckets.len(), 10);
+        // None is idle, so the least recently used one makes room
+        let now = start + Duration::from_millis(10);
+        assert!(buckets.take(key(10), &limit, now).allowed);
+        assert_eq!(buckets.buckets.len(), 10);
+        assert!(!buckets.buckets.contains_key(&key(0)));
+        // Known clients keep their bucket
+        buckets.take(key(9), &limit, now);
+        assert!(!buckets.take(key(9), &limit, now).allowed);
+    }
+}
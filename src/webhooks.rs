@@ -0,0 +1,548 @@
+use crate::db::{
+    get_webhooks, insert_webhook_delivery, record_delivery_attempt, DeliveryAttempt,
+    DeliveryStatus, SavedArtwork, Webhook, WebhookDelivery,
+};
+use crate::metrics;
+use hmac::{Hmac, Mac, NewMac};
+use mongodb::Database;
+use reqwest::redirect::Policy;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Header carrying the delivery id, the same across retries so receivers can deduplicate
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+
+/// Header carrying the unix timestamp (seconds) the request was signed at
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+
+/// Header carrying `sha256=` and the hex HMAC-SHA256 of `{timestamp}.{body}`, keyed by the secret
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Shortest secret accepted when registering a webhook
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// Kind of content change a webhook can subscribe to
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEvent {
+    /// The artwork became visible
+    Added,
+    /// A visible artwork moved to another rating
+    Rerated,
+    /// A visible artwork was taken down: moderated out, rejected or gone 404
+    Removed,
+}
+
+/// A change of the visible content written by a db sync
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ContentChange {
+    pub event: ContentEvent,
+    pub art_id: i64,
+    pub characters: Vec<String>,
+    /// `moderate.type` after the change
+    pub rating: Option<String>,
+    /// `moderate.type` before the change, none for added artworks
+    pub previous_rating: Option<String>,
+}
+
+impl ContentChange {
+    /// The change made by a saved artwork, if the save changed what is visible
+    pub fn from_saved(saved: &SavedArtwork) -> Option<ContentChange> {
+        let event = match (saved.was_visible, saved.is_visible()) {
+            (false, true) => ContentEvent::Added,
+            (true, false) => ContentEvent::Removed,
+            (true, true) if saved.previous_rating.as_deref() != saved.rating() => {
+                ContentEvent::Rerated
+            }
+            _ => return None,
+        };
+        Some(ContentChange {
+            event,
+            art_id: saved.artwork.art_id,
+            characters: saved.artwork.characters.clone(),
+            rating: saved.rating().map(str::to_owned),
+            previous_rating: match event {
+                ContentEvent::Added => None,
+                _ => saved.previous_rating.clone(),
+            },
+        })
+    }
+}
+
+/// Hex HMAC-SHA256 of a message
+pub fn signature(secret: &[u8], message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(message);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// How often and how patiently deliveries are retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled before every further one
+    pub base_delay: Duration,
+    /// Longest delay between attempts
+    pub max_delay: Duration,
+    /// Timeout of a single attempt
+    pub timeout: Duration,
+}
+
+impl RetryPolicy {
+    /// `WEBHOOK_MAX_ATTEMPTS` (default 6), `WEBHOOK_RETRY_BASE_SECS` (default 5) and
+    /// `WEBHOOK_TIMEOUT_SECS` (default 10)
+    pub fn from_env() -> Self {
+        let env_u64 = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|val| val.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        RetryPolicy {
+            max_attempts: u32::try_from(env_u64("WEBHOOK_MAX_ATTEMPTS", 6))
+                .unwrap_or(u32::MAX)
+                .max(1),
+            base_delay: Duration::from_secs(env_u64("WEBHOOK_RETRY_BASE_SECS", 5)),
+            max_delay: Duration::from_secs(60 * 60),
+            timeout: Duration::from_secs(env_u64("WEBHOOK_TIMEOUT_SECS", 10)),
+        }
+    }
+
+    /// Delay before retrying after the `attempt`th attempt (from 1), none once attempts run out
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let factor = 1_u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        Some(
+            self.base_delay
+                .checked_mul(factor)
+                .unwrap_or(self.max_delay)
+                .min(self.max_delay),
+        )
+    }
+}
+
+/// Whether a failed attempt may succeed later: connection errors, timeouts,
+/// 408, 429 and server errors. Other responses are final
+pub fn is_retryable(attempt: &DeliveryAttempt) -> bool {
+    match attempt
+        .status_code
+        .and_then(|code| StatusCode::from_u16(code).ok())
+    {
+        Some(status) => {
+            status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        None => true,
+    }
+}
+
+/// Webhooks delivers content changes to the registered webhooks.
+///
+/// Each db sync sends one signed POST per webhook, with the changes it subscribed to.
+/// Deliveries run in the background and are retried with exponential backoff;
+/// every attempt is recorded in the delivery log. Pending retries don't survive a restart
+#[derive(Clone)]
+pub struct Webhooks {
+    client: Client,
+    policy: RetryPolicy,
+}
+
+impl Webhooks {
+    pub fn new(policy: RetryPolicy) -> Result<Self, reqwest::Error> {
+        let client = Client::builder()
+            .timeout(policy.timeout)
+            // A redirect would resend the signed body to wherever the target points
+            .redirect(Policy::none())
+            .user_agent(concat!("genshin-gallery-api/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Webhooks { client, policy })
+    }
+
+    /// Starts the deliveries of the changes of a db sync
+    pub async fn dispatch(&self, db: &Database, changes: &[ContentChange]) {
+        if changes.is_empty() {
+            return;
+        }
+        let webhooks = match get_webhooks(db).await {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                log::error!("Get webhooks {:?}", e);
+                return;
+            }
+        };
+        for webhook in webhooks {
+            let changes: Vec<&ContentChange> = changes
+                .iter()
+                .filter(|change| webhook.events.contains(&change.event))
+                .collect();
+            if changes.is_empty() {
+                continue;
+            }
+            let mut events: Vec<ContentEvent> = changes.iter().map(|change| change.event).collect();
+            events.sort();
+            events.dedup();
+            let now = unix_now();
+            let delivery = WebhookDelivery {
+                id: uuid::Uuid::new_v4().to_string(),
+                webhook_id: webhook.id.clone(),
+                url: webhook.url.clone(),
+                events,
+                art_ids: changes.iter().map(|change| change.art_id).collect(),
+                status: DeliveryStatus::Pending,
+                attempts: vec![],
+                created_at: now,
+            };
+            let body = json!({
+                "id": delivery.id,
+                "created_at": now,
+                "changes": changes,
+            })
+            .to_string();
+            if let Err(e) = insert_webhook_delivery(db, &delivery).await {
+                log::warn!("Insert webhook delivery {:?}", e);
+            }
+            let webhooks = self.clone();
+            let db = db.clone();
+            tokio::spawn(async move { webhooks.deliver(&db, &webhook, &delivery.id, &body).await });
+        }
+    }
+
+    /// Sends a delivery until it succeeds, fails permanently or runs out of attempts,
+    /// recording every attempt in the delivery log
+    async fn deliver(&self, db: &Database, webhook: &Webhook, delivery_id: &str, body: &str) {
+        self.deliver_with(webhook, delivery_id, body, |attempt, status| async move {
+            if let Err(e) = record_delivery_attempt(db, delivery_id, &attempt, status).await {
+                log::warn!("Record webhook delivery attempt {:?}", e);
+            }
+        })
+        .await;
+    }
+
+    /// The retry loop of `deliver`, handing each attempt and the status it leaves the delivery
+    /// in to `record`. Returns the final status
+    async fn deliver_with<R, Fut>(
+        &self,
+        webhook: &Webhook,
+        delivery_id: &str,
+        body: &str,
+        record: R,
+    ) -> DeliveryStatus
+    where
+        R: Fn(DeliveryAttempt, DeliveryStatus) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut number = 1;
+        loop {
+            let attempt = self.attempt(webhook, delivery_id, body).await;
+            let delay = if attempt.error.is_none() {
+                None
+            } else if is_retryable(&attempt) {
+                self.policy.delay(number)
+            } else {
+                None
+            };
+            let status = match (&attempt.error, delay) {
+                (None, _) => DeliveryStatus::Delivered,
+                (Some(_), Some(_)) => DeliveryStatus::Pending,
+                (Some(_), None) => DeliveryStatus::Failed,
+            };
+            metrics::record_webhook_attempt(match status {
+                DeliveryStatus::Delivered => "delivered",
+                DeliveryStatus::Pending => "retry",
+                DeliveryStatus::Failed => "failed",
+            });
+            if status == DeliveryStatus::Failed {
+                log::warn!(
+                    "Webhook delivery {} to {} failed after {} attempts: {}",
+                    delivery_id,
+                    webhook.url,
+                    number,
+                    attempt.error.as_deref().unwrap_or_default()
+                );
+            }
+            record(attempt, status).await;
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return status,
+            }
+            number += 1;
+        }
+    }
+
+    /// POSTs a signed delivery once. Any response but 2xx counts as an error
+    async fn attempt(&self, webhook: &Webhook, delivery_id: &str, body: &str) -> DeliveryAttempt {
+        let at = unix_now();
+        let signature = signature(
+            webhook.secret.as_bytes(),
+            format!("{}.{}", at, body).as_bytes(),
+        );
+        let started = Instant::now();
+        let result = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery_id)
+            .header(TIMESTAMP_HEADER, at.to_string())
+            .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+            .body(body.to_owned())
+            .send()
+            .await;
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match result {
+            Ok(response) if response.status().is_success() => DeliveryAttempt {
+                at,
+                status_code: Some(response.status().as_u16()),
+                error: None,
+                duration_ms,
+            },
+            Ok(response) => DeliveryAttempt {
+                at,
+                status_code: Some(response.status().as_u16()),
+                error: Some(format!("unexpected status {}", response.status())),
+                duration_ms,
+            },
+            Err(e) => DeliveryAttempt {
+                at,
+                status_code: None,
+                error: Some(e.to_string()),
+                duration_ms,
+            },
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_retryable, signature, ContentChange, ContentEvent, RetryPolicy, Webhooks,
+        DELIVERY_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+    use crate::artwork::ArtworkInfo;
+    use crate::db::{DeliveryAttempt, DeliveryStatus, SavedArtwork, Webhook};
+    use serde_json::json;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn saved(
+        was_visible: bool,
+        previous: Option<&str>,
+        rating: &str,
+        status: &str,
+    ) -> SavedArtwork {
+        let artwork: ArtworkInfo = serde_json::from_value(json!({
+            "art_id": 96664758_i64,
+            "title": "",
+            "tag_str": "",
+            "characters": ["Ganyu"],
+            "view_count": 0,
+            "like_count": 0,
+            "love_count": 0,
+            "artist_id": 1_i64,
+            "upload_timestamp": 0_i64,
+            "moderate": { "type": rating, "status": status },
+        }))
+        .unwrap();
+        SavedArtwork {
+            artwork,
+            existed: previous.is_some(),
+            was_visible,
+            previous_rating: previous.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_content_change_from_saved() {
+        let event = |saved: SavedArtwork| ContentChange::from_saved(&saved).map(|c| c.event);
+        assert_eq!(
+            event(saved(false, None, "SFW", "PASS")),
+            Some(ContentEvent::Added)
+        );
+        assert_eq!(
+            event(saved(false, Some("SFW"), "SFW", "PUSH")),
+            Some(ContentEvent::Added)
+        );
+        assert_eq!(
+            event(saved(true, Some("SFW"), "R18", "PASS")),
+            Some(ContentEvent::Rerated)
+        );
+        assert_eq!(
+            event(saved(true, Some("SFW"), "SFW", "BLOCK")),
+            Some(ContentEvent::Removed)
+        );
+        assert_eq!(event(saved(true, Some("SFW"), "SFW", "PASS")), None);
+        assert_eq!(event(saved(false, None, "SFW", "PENDING")), None);
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(12),
+            timeout: Duration::from_secs(1),
+        };
+        assert_eq!(policy.delay(1), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(2), Some(Duration::from_secs(10)));
+        assert_eq!(policy.delay(3), Some(Duration::from_secs(12)));
+        assert_eq!(policy.delay(4), None);
+
+        let attempt = |status_code: Option<u16>| DeliveryAttempt {
+            at: 0,
+            status_code,
+            error: Some("error".to_owned()),
+            duration_ms: 0,
+        };
+        assert!(is_retryable(&attempt(None)));
+        assert!(is_retryable(&attempt(Some(503))));
+        assert!(is_retryable(&attempt(Some(429))));
+        assert!(!is_retryable(&attempt(Some(404))));
+    }
+
+    /// Answers each connection with the next status and returns the requests it received
+    async fn stand_in(statuses: Vec<u16>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut requests = vec![];
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buffer = [0; 4096];
+                // Headers and the small body arrive before the client waits for a response
+                while !String::from_utf8_lossy(&request).contains("}") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let response = format!(
+                    "HTTP/1.1 {} Stand-in\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request).into_owned());
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn test_delivery_retries_against_stand_in() {
+        let (url, handle) = stand_in(vec![503, 204]).await;
+        let webhooks = Webhooks::new(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let webhook = Webhook {
+            id: "hook".to_owned(),
+            url,
+            events: vec![],
+            secret: "0123456789abcdef".to_owned(),
+            created_at: 0,
+        };
+        let body = r#"{"id":"delivery","changes":[]}"#;
+
+        let log = Mutex::new(vec![]);
+        let status = webhooks
+            .deliver_with(&webhook, "delivery", body, |attempt, status| {
+                log.lock().unwrap().push((attempt, status));
+                async {}
+            })
+            .await;
+        assert_eq!(status, DeliveryStatus::Delivered);
+        let log = log.into_inner().unwrap();
+        assert_eq!(log.len(), 2);
+        let (failed, status) = &log[0];
+        assert_eq!(failed.status_code, Some(503));
+        assert!(failed.error.is_some());
+        assert_eq!(*status, DeliveryStatus::Pending);
+        let (delivered, status) = &log[1];
+        assert_eq!(delivered.status_code, Some(204));
+        assert!(delivered.error.is_none());
+        assert_eq!(*status, DeliveryStatus::Delivered);
+
+        let requests = handle.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let request = requests[1].to_lowercase();
+        let timestamp = delivered.at.to_string();
+        let expected = signature(
+            webhook.secret.as_bytes(),
+            format!("{}.{}", timestamp, body).as_bytes(),
+        );
+        assert!(request.starts_with("post /hook "));
+        assert!(request.contains(&format!("{}: delivery", DELIVERY_HEADER)));
+        assert!(request.contains(&format!("{}: {}", TIMESTAMP_HEADER, timestamp)));
+        assert!(request.contains(&format!("{}: sha256={}", SIGNATURE_HEADER, expected)));
+        assert!(request.ends_with(body));
+    }
+
+    #[tokio::test]
+    async fn test_delivery_gives_up() {
+        let (url, handle) = stand_in(vec![503, 503, 404]).await;
+        let webhooks = Webhooks::new(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let webhook = Webhook {
+            id: "hook".to_owned(),
+            url,
+            events: vec![],
+            secret: "0123456789abcdef".to_owned(),
+            created_at: 0,
+        };
+        let statuses = Mutex::new(vec![]);
+        let record = |_, status| {
+            statuses.lock().unwrap().push(status);
+            async {}
+        };
+        // Out of attempts
+        let status = webhooks.deliver_with(&webhook, "a", "{}", record).await;
+        assert_eq!(status, DeliveryStatus::Failed);
+        // Not retryable
+        let status = webhooks.deliver_with(&webhook, "b", "{}", record).await;
+        assert_eq!(status, DeliveryStatus::Failed);
+        assert_eq!(
+            statuses.into_inner().unwrap(),
+            vec![
+                DeliveryStatus::Pending,
+                DeliveryStatus::Failed,
+                DeliveryStatus::Failed
+            ]
+        );
+        assert_eq!(handle.await.unwrap().len(), 3);
+    }
+}